@@ -1,3 +1,634 @@
-enum Command {}
+use defmt::Format;
+use embassy::time::{Instant, TICKS_PER_SECOND};
+use num_enum::TryFromPrimitive;
+use rgb::RGB8;
 
-pub type PacketData = [u8; 17];
+use crate::color::{Gamma, GAMMA_CHUNK_SIZE};
+use crate::effects::Effect;
+use crate::encoder::{Curve, EncoderId, CURVE_TABLE_SIZE, NUM_ENCODERS};
+use crate::gesture::{Gesture, GestureConfig};
+use crate::ghosting::Ghosting;
+use crate::i2c::Diagnostics;
+use crate::input::{Key, ScanConfig};
+use crate::ledmap::Indicator;
+use crate::leds::NUM_LEDS;
+use crate::power::CurrentEstimate;
+use crate::util::bitarray::BitArray;
+
+/// Frames in both directions are `[type, len, payload..., pec]`, with `len` the payload
+/// length and `pec` an SMBus PEC over the whole transfer, see [`crate::crc::pec`].
+/// The PEC is checked and added by the I2C driver, it isn't part of [`Frame`] contents.
+///
+/// Every read transaction pops a whole frame from the queue, however many bytes the host
+/// clocks out. So the host can't read the header first and the rest later: it has to read
+/// [`MAX_MESSAGE_FRAME_SIZE`] bytes at once, the bytes after the frame read as 0
+pub const HEADER_SIZE: usize = 2;
+pub const PEC_SIZE: usize = 1;
+
+/// Largest payload accepted from the host, enough for [`Command::SetLeds`] on every LED.
+/// Longer frames are rejected with [`ErrorCode::FrameTooLong`]
+pub const MAX_PAYLOAD: usize = 1 + 3 * NUM_LEDS;
+pub const MAX_FRAME_SIZE: usize = HEADER_SIZE + MAX_PAYLOAD + PEC_SIZE;
+
+/// Payload of all commands except [`Command::SetLeds`]. Shorter payloads are zero-padded
+const FIXED_PAYLOAD: usize = 16;
+
+/// Largest payload of a [`Message`], [`Message::Diagnostics`]
+pub const MAX_MESSAGE_PAYLOAD: usize = Diagnostics::SIZE;
+pub const MAX_MESSAGE_FRAME_SIZE: usize = HEADER_SIZE + MAX_MESSAGE_PAYLOAD + PEC_SIZE;
+
+/// A frame received from the host
+pub type Frame = heapless::Vec<u8, MAX_FRAME_SIZE>;
+
+/// A frame sent to the host
+pub type MessageFrame = heapless::Vec<u8, MAX_MESSAGE_FRAME_SIZE>;
+
+/// Firmware version reported to the host, taken from `Cargo.toml`
+pub const VERSION: [u8; 3] = [
+    parse_u8(env!("CARGO_PKG_VERSION_MAJOR")),
+    parse_u8(env!("CARGO_PKG_VERSION_MINOR")),
+    parse_u8(env!("CARGO_PKG_VERSION_PATCH")),
+];
+
+const fn parse_u8(s: &str) -> u8 {
+    let bytes = s.as_bytes();
+    let mut res = 0u8;
+    let mut i = 0;
+    while i < bytes.len() {
+        res = res * 10 + (bytes[i] - b'0');
+        i += 1;
+    }
+    res
+}
+
+/// The type byte of every frame sent by the host
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive, Format)]
+#[repr(u8)]
+pub enum CommandId {
+    SetLed = 0x01,
+    SetLedRange = 0x02,
+    SetAllLeds = 0x03,
+    SetStatusLed = 0x04,
+    SetBrightness = 0x05,
+    SetGamma = 0x06,
+    SetGammaTable = 0x07,
+    SetPowerLimit = 0x08,
+    SetKeyLed = 0x09,
+    SetEncoderLed = 0x0A,
+    SetIndicatorLed = 0x0B,
+    StartEffect = 0x0C,
+    SetFeedback = 0x0D,
+    SetKeyFeedback = 0x0E,
+    SetLeds = 0x0F,
+    RequestKeyState = 0x10,
+    RequestVersion = 0x11,
+    RequestCurrent = 0x12,
+    RequestTick = 0x13,
+    RequestDiagnostics = 0x14,
+    ClearDiagnostics = 0x15,
+    SetDebounce = 0x20,
+    SetEncoderAcceleration = 0x21,
+    SetGestureConfig = 0x22,
+    SetGhosting = 0x23,
+    SetScanConfig = 0x24,
+    Reset = 0xFF,
+}
+
+/// A command sent from the host.
+///
+/// The layouts below are the frame payloads, after `[id, len]`. Trailing zeros may be
+/// left out. Colors are sent as `r, g, b`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Command {
+    /// `[idx, r, g, b]`
+    SetLed {
+        idx: u8,
+        color: RGB8,
+    },
+    /// `[start, end, r, g, b]`, `end` is exclusive
+    SetLedRange {
+        start: u8,
+        end: u8,
+        color: RGB8,
+    },
+    /// `[r, g, b]`
+    SetAllLeds {
+        color: RGB8,
+    },
+    /// `[start, r, g, b, r, g, b, ...]`, set `len` consecutive LEDs in one frame
+    SetLeds {
+        start: u8,
+        len: u8,
+        colors: [RGB8; NUM_LEDS],
+    },
+    /// `[on]`
+    SetStatusLed {
+        on: bool,
+    },
+    /// `[brightness]`, 255 is full brightness
+    SetBrightness {
+        brightness: u8,
+    },
+    /// `[gamma]`, see [`Gamma`]
+    SetGamma {
+        gamma: Gamma,
+    },
+    /// `[offset, entries...]`, part of the custom gamma table
+    SetGammaTable {
+        offset: u8,
+        data: [u8; GAMMA_CHUNK_SIZE],
+    },
+    /// `[limit_lo, limit_hi]`, current limit for the whole strip in mA, 0 for no limit
+    SetPowerLimit {
+        limit_ma: u16,
+    },
+    /// `[key, r, g, b]`, set the LED under a [`Key`]
+    SetKeyLed {
+        key: Key,
+        color: RGB8,
+    },
+    /// `[encoder, r, g, b]`, set the LED next to an encoder
+    SetEncoderLed {
+        encoder: EncoderId,
+        color: RGB8,
+    },
+    /// `[indicator, r, g, b]`, set the LED of an [`Indicator`]
+    SetIndicatorLed {
+        indicator: Indicator,
+        color: RGB8,
+    },
+    /// `[start, end, effect, params...]`, `end` is exclusive. Periods and durations
+    /// are little endian u16 in ms. Effect and params are one of
+    /// - `0, r, g, b`: [`Effect::Solid`]
+    /// - `1, r, g, b, period, duty`: [`Effect::Blink`]
+    /// - `2, r, g, b, period`: [`Effect::Pulse`]
+    /// - `3, r, g, b, duration`: [`Effect::Fade`]
+    /// - `4, r, g, b, period`: [`Effect::Chase`]
+    /// - `5, period`: [`Effect::Rainbow`]
+    StartEffect {
+        start: u8,
+        end: u8,
+        effect: Effect,
+    },
+    /// `[enabled, r, g, b]`, light the LED of held keys locally, in the given color
+    SetFeedback {
+        enabled: bool,
+        color: RGB8,
+    },
+    /// `[key, enabled]`, enable or disable local feedback for a single key
+    SetKeyFeedback {
+        key: Key,
+        enabled: bool,
+    },
+    /// `[key, window_lo, window_hi]`, debounce window in ms for a key, or for all keys
+    /// if `key` is 0
+    SetDebounce {
+        key: Key,
+        window_ms: u16,
+    },
+    /// `[encoder, curve, params...]`, with curve and params one of
+    /// - `0`: acceleration off
+    /// - `1, threshold, gain`: [`Curve::Linear`]
+    /// - `2, threshold, doubling`: [`Curve::Exponential`]
+    /// - `3, multipliers...`: [`Curve::Table`]
+    SetEncoderAcceleration {
+        encoder: EncoderId,
+        curve: Option<Curve>,
+    },
+    /// `[key, long_press, double_tap, repeat_delay, repeat_interval]`, each time a
+    /// little endian u16 in ms, 0 to disable. See [`GestureConfig`]
+    SetGestureConfig {
+        key: Key,
+        config: GestureConfig,
+    },
+    /// `[mode]`, how to deal with keys that might be ghosts, see [`Ghosting`]
+    SetGhosting {
+        ghosting: Ghosting,
+    },
+    /// `[rate_hz, settle_us]`, rate_hz a little endian u16, at least 1. See [`ScanConfig`]
+    SetScanConfig(ScanConfig),
+    /// Replied to with [`Message::KeyState`], so the host can re-sync after
+    /// a reboot or a [`Message::EventsLost`]
+    RequestKeyState,
+    RequestVersion,
+    RequestCurrent,
+    RequestTick,
+    /// Replied to with [`Message::Diagnostics`]
+    RequestDiagnostics,
+    /// Reset all diagnostics counters to zero
+    ClearDiagnostics,
+    Reset,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum DecodeError {
+    UnknownCommand(u8),
+    InvalidArgument,
+    /// The frame is shorter than its header, or `len` doesn't match the payload
+    BadLength,
+}
+
+impl DecodeError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            DecodeError::UnknownCommand(_) => ErrorCode::UnknownCommand,
+            DecodeError::InvalidArgument => ErrorCode::InvalidArgument,
+            DecodeError::BadLength => ErrorCode::BadLength,
+        }
+    }
+}
+
+/// Sent to the host in [`Message::Error`] when a command can't be executed
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive, Format)]
+#[repr(u8)]
+pub enum ErrorCode {
+    UnknownCommand = 0x01,
+    InvalidArgument = 0x02,
+    /// The addressed key has no LED
+    NoLed = 0x03,
+    /// All effect slots are in use, see [`crate::effects::MAX_EFFECTS`]
+    TooManyEffects = 0x04,
+    /// The payload was longer than [`MAX_PAYLOAD`]
+    FrameTooLong = 0x05,
+    BadLength = 0x06,
+    /// The frame was corrupted on the bus and ignored
+    BadPec = 0x07,
+}
+
+fn color_at(data: &[u8]) -> RGB8 {
+    RGB8::new(data[0], data[1], data[2])
+}
+
+fn u16_at(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn decode_effect(args: &[u8]) -> Result<Effect, DecodeError> {
+    let color = color_at(&args[1..]);
+    let effect = match args[0] {
+        0 => Effect::Solid(color),
+        1 => Effect::Blink {
+            color,
+            period_ms: u16_at(&args[4..]),
+            duty: args[6],
+        },
+        2 => Effect::Pulse {
+            color,
+            period_ms: u16_at(&args[4..]),
+        },
+        3 => Effect::Fade {
+            color,
+            duration_ms: u16_at(&args[4..]),
+        },
+        4 => Effect::Chase {
+            color,
+            period_ms: u16_at(&args[4..]),
+        },
+        5 => Effect::Rainbow {
+            period_ms: u16_at(&args[1..]),
+        },
+        _ => return Err(DecodeError::InvalidArgument),
+    };
+    Ok(effect)
+}
+
+fn decode_set_leds(payload: &[u8]) -> Result<Command, DecodeError> {
+    let (start, data) = payload.split_first().ok_or(DecodeError::BadLength)?;
+    if data.len() % 3 != 0 {
+        return Err(DecodeError::BadLength);
+    }
+    let len = data.len() / 3;
+    if *start as usize + len > NUM_LEDS {
+        return Err(DecodeError::InvalidArgument);
+    }
+    let mut colors = [RGB8::default(); NUM_LEDS];
+    for (color, rgb) in colors.iter_mut().zip(data.chunks_exact(3)) {
+        *color = color_at(rgb);
+    }
+    Ok(Command::SetLeds {
+        start: *start,
+        len: len as u8,
+        colors,
+    })
+}
+
+impl Command {
+    pub fn decode(frame: &[u8]) -> Result<Self, DecodeError> {
+        if frame.len() < HEADER_SIZE || frame[1] as usize != frame.len() - HEADER_SIZE {
+            return Err(DecodeError::BadLength);
+        }
+        let id =
+            CommandId::try_from(frame[0]).map_err(|e| DecodeError::UnknownCommand(e.number))?;
+        let payload = &frame[HEADER_SIZE..];
+        if id == CommandId::SetLeds {
+            return decode_set_leds(payload);
+        }
+        if payload.len() > FIXED_PAYLOAD {
+            return Err(DecodeError::BadLength);
+        }
+        let mut args = [0; FIXED_PAYLOAD];
+        args[..payload.len()].copy_from_slice(payload);
+        let args = &args;
+        let cmd = match id {
+            CommandId::SetLed => {
+                if args[0] as usize >= NUM_LEDS {
+                    return Err(DecodeError::InvalidArgument);
+                }
+                Command::SetLed {
+                    idx: args[0],
+                    color: color_at(&args[1..]),
+                }
+            }
+            CommandId::SetLedRange => {
+                if args[0] > args[1] || args[1] as usize > NUM_LEDS {
+                    return Err(DecodeError::InvalidArgument);
+                }
+                Command::SetLedRange {
+                    start: args[0],
+                    end: args[1],
+                    color: color_at(&args[2..]),
+                }
+            }
+            CommandId::SetAllLeds => Command::SetAllLeds {
+                color: color_at(args),
+            },
+            // Variable length, handled above
+            CommandId::SetLeds => unreachable!(),
+            CommandId::SetStatusLed => Command::SetStatusLed { on: args[0] != 0 },
+            CommandId::SetBrightness => Command::SetBrightness {
+                brightness: args[0],
+            },
+            CommandId::SetGamma => Command::SetGamma {
+                gamma: Gamma::try_from(args[0]).map_err(|_| DecodeError::InvalidArgument)?,
+            },
+            CommandId::SetGammaTable => {
+                let mut data = [0; GAMMA_CHUNK_SIZE];
+                data.copy_from_slice(&args[1..1 + GAMMA_CHUNK_SIZE]);
+                Command::SetGammaTable {
+                    offset: args[0],
+                    data,
+                }
+            }
+            CommandId::SetPowerLimit => Command::SetPowerLimit {
+                limit_ma: u16_at(args),
+            },
+            CommandId::SetKeyLed => Command::SetKeyLed {
+                key: Key::try_from(args[0]).map_err(|_| DecodeError::InvalidArgument)?,
+                color: color_at(&args[1..]),
+            },
+            CommandId::SetEncoderLed => Command::SetEncoderLed {
+                encoder: EncoderId::try_from(args[0]).map_err(|_| DecodeError::InvalidArgument)?,
+                color: color_at(&args[1..]),
+            },
+            CommandId::SetIndicatorLed => Command::SetIndicatorLed {
+                indicator: Indicator::try_from(args[0])
+                    .map_err(|_| DecodeError::InvalidArgument)?,
+                color: color_at(&args[1..]),
+            },
+            CommandId::StartEffect => {
+                if args[0] > args[1] || args[1] as usize > NUM_LEDS {
+                    return Err(DecodeError::InvalidArgument);
+                }
+                Command::StartEffect {
+                    start: args[0],
+                    end: args[1],
+                    effect: decode_effect(&args[2..])?,
+                }
+            }
+            CommandId::SetFeedback => Command::SetFeedback {
+                enabled: args[0] != 0,
+                color: color_at(&args[1..]),
+            },
+            CommandId::SetKeyFeedback => Command::SetKeyFeedback {
+                key: Key::try_from(args[0]).map_err(|_| DecodeError::InvalidArgument)?,
+                enabled: args[1] != 0,
+            },
+            CommandId::SetDebounce => Command::SetDebounce {
+                key: Key::try_from(args[0]).map_err(|_| DecodeError::InvalidArgument)?,
+                window_ms: u16::from_le_bytes([args[1], args[2]]),
+            },
+            CommandId::SetEncoderAcceleration => {
+                let encoder =
+                    EncoderId::try_from(args[0]).map_err(|_| DecodeError::InvalidArgument)?;
+                let params = &args[2..];
+                let curve = match args[1] {
+                    0 => None,
+                    1 => Some(Curve::Linear {
+                        threshold: params[0],
+                        gain: params[1],
+                    }),
+                    2 => Some(Curve::Exponential {
+                        threshold: params[0],
+                        doubling: params[1],
+                    }),
+                    3 => {
+                        let mut table = [0; CURVE_TABLE_SIZE];
+                        table.copy_from_slice(&params[..CURVE_TABLE_SIZE]);
+                        Some(Curve::Table(table))
+                    }
+                    _ => return Err(DecodeError::InvalidArgument),
+                };
+                Command::SetEncoderAcceleration { encoder, curve }
+            }
+            CommandId::SetGestureConfig => Command::SetGestureConfig {
+                key: Key::try_from(args[0]).map_err(|_| DecodeError::InvalidArgument)?,
+                config: GestureConfig {
+                    long_press_ms: u16_at(&args[1..]),
+                    double_tap_ms: u16_at(&args[3..]),
+                    repeat_delay_ms: u16_at(&args[5..]),
+                    repeat_interval_ms: u16_at(&args[7..]),
+                },
+            },
+            CommandId::SetGhosting => Command::SetGhosting {
+                ghosting: Ghosting::try_from(args[0]).map_err(|_| DecodeError::InvalidArgument)?,
+            },
+            CommandId::SetScanConfig => {
+                let rate_hz = u16_at(args);
+                if rate_hz == 0 {
+                    return Err(DecodeError::InvalidArgument);
+                }
+                Command::SetScanConfig(ScanConfig {
+                    rate_hz,
+                    settle_us: args[2],
+                })
+            }
+            CommandId::RequestKeyState => Command::RequestKeyState,
+            CommandId::RequestVersion => Command::RequestVersion,
+            CommandId::RequestCurrent => Command::RequestCurrent,
+            CommandId::RequestTick => Command::RequestTick,
+            CommandId::RequestDiagnostics => Command::RequestDiagnostics,
+            CommandId::ClearDiagnostics => Command::ClearDiagnostics,
+            CommandId::Reset => Command::Reset,
+        };
+        Ok(cmd)
+    }
+}
+
+/// Event timestamp: the low 32 bits of the MCU tick counter, see [`Message::Tick`]
+pub fn timestamp(instant: Instant) -> u32 {
+    instant.as_ticks() as u32
+}
+
+/// The type byte of every frame sent to the host
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive, Format)]
+#[repr(u8)]
+pub enum MessageId {
+    /// Sent when the host reads and there is nothing queued
+    None = 0x00,
+    KeyPress = 0x01,
+    KeyRelease = 0x02,
+    Encoder = 0x03,
+    Gesture = 0x04,
+    EventsLost = 0x05,
+    KeyState = 0x10,
+    Version = 0x11,
+    Current = 0x12,
+    Tick = 0x13,
+    Diagnostics = 0x14,
+    Error = 0xFE,
+}
+
+/// A message sent to the host. Framed the same way as [`Command`], the layouts
+/// below are the payloads.
+///
+/// Event times are [`timestamp`]s of when the event happened, little endian.
+#[derive(Clone, PartialEq, Format)]
+pub enum Message {
+    /// Empty payload
+    None,
+    /// `[key, time..., uncertain]`, uncertain is 1 if the key might be a ghost,
+    /// see [`crate::ghosting::Ghosting::Flag`]
+    KeyPress {
+        key: Key,
+        time: u32,
+        uncertain: bool,
+    },
+    /// `[key, time...]`
+    KeyRelease { key: Key, time: u32 },
+    /// `[encoder, steps, time...]`, steps as a signed byte, positive is clockwise
+    Encoder {
+        encoder: EncoderId,
+        steps: i8,
+        time: u32,
+    },
+    /// `[key, gesture, time...]`
+    Gesture {
+        key: Key,
+        gesture: Gesture,
+        time: u32,
+    },
+    /// `[count_lo, count_hi]`, messages were dropped because the TX queue was full.
+    /// The host should re-sync its state
+    EventsLost { count: u16 },
+    /// `[key bits..., encoder positions...]`, one bit per [`Key`], LSB first, followed by
+    /// the positions of all encoders as little endian i16, wrapping, in [`EncoderId`] order.
+    /// A position is the sum of the [`Message::Encoder`] steps, so it includes acceleration
+    KeyState {
+        keys: BitArray<64>,
+        encoders: [i16; NUM_ENCODERS],
+    },
+    /// `[major, minor, patch]`
+    Version,
+    /// `[requested, output, limit]`, each a little endian u16 in mA
+    Current(CurrentEstimate),
+    /// `[ticks..., ticks_per_second...]`, the current MCU tick count as a little endian u64,
+    /// and the tick rate as a little endian u32
+    Tick { ticks: u64 },
+    /// `[counters...]`, little endian u32 in the order of the [`Diagnostics`] fields
+    Diagnostics(Diagnostics),
+    /// `[command, code]`, the command that failed and why
+    Error { command: u8, code: ErrorCode },
+}
+
+impl Message {
+    pub fn encode(&self) -> MessageFrame {
+        // `[id, payload...]`, the length is inserted below
+        let mut res = [0; 1 + MAX_MESSAGE_PAYLOAD];
+        let len = match self {
+            Message::None => 0,
+            Message::KeyPress {
+                key,
+                time,
+                uncertain,
+            } => {
+                res[0] = MessageId::KeyPress as u8;
+                res[1] = *key as u8;
+                res[2..6].copy_from_slice(&time.to_le_bytes());
+                res[6] = *uncertain as u8;
+                6
+            }
+            Message::KeyRelease { key, time } => {
+                res[0] = MessageId::KeyRelease as u8;
+                res[1] = *key as u8;
+                res[2..6].copy_from_slice(&time.to_le_bytes());
+                5
+            }
+            Message::Encoder {
+                encoder,
+                steps,
+                time,
+            } => {
+                res[0] = MessageId::Encoder as u8;
+                res[1] = *encoder as u8;
+                res[2] = *steps as u8;
+                res[3..7].copy_from_slice(&time.to_le_bytes());
+                6
+            }
+            Message::Gesture { key, gesture, time } => {
+                res[0] = MessageId::Gesture as u8;
+                res[1] = *key as u8;
+                res[2] = *gesture as u8;
+                res[3..7].copy_from_slice(&time.to_le_bytes());
+                6
+            }
+            Message::EventsLost { count } => {
+                res[0] = MessageId::EventsLost as u8;
+                res[1..3].copy_from_slice(&count.to_le_bytes());
+                2
+            }
+            Message::KeyState { keys, encoders } => {
+                res[0] = MessageId::KeyState as u8;
+                res[1..9].copy_from_slice(keys.as_bytes());
+                for (i, pos) in encoders.iter().enumerate() {
+                    res[9 + 2 * i..11 + 2 * i].copy_from_slice(&pos.to_le_bytes());
+                }
+                16
+            }
+            Message::Version => {
+                res[0] = MessageId::Version as u8;
+                res[1..4].copy_from_slice(&VERSION);
+                3
+            }
+            Message::Current(estimate) => {
+                res[0] = MessageId::Current as u8;
+                res[1..3].copy_from_slice(&estimate.requested_ma.to_le_bytes());
+                res[3..5].copy_from_slice(&estimate.output_ma.to_le_bytes());
+                res[5..7].copy_from_slice(&estimate.limit_ma.to_le_bytes());
+                6
+            }
+            Message::Tick { ticks } => {
+                res[0] = MessageId::Tick as u8;
+                res[1..9].copy_from_slice(&ticks.to_le_bytes());
+                res[9..13].copy_from_slice(&(TICKS_PER_SECOND as u32).to_le_bytes());
+                12
+            }
+            Message::Diagnostics(diagnostics) => {
+                res[0] = MessageId::Diagnostics as u8;
+                res[1..1 + Diagnostics::SIZE].copy_from_slice(&diagnostics.to_bytes());
+                Diagnostics::SIZE as u8
+            }
+            Message::Error { command, code } => {
+                res[0] = MessageId::Error as u8;
+                res[1] = *command;
+                res[2] = *code as u8;
+                2
+            }
+        };
+        let mut frame = MessageFrame::new();
+        // Can't overflow, the frame has room for the header and the largest payload
+        let _ = frame.push(res[0]);
+        let _ = frame.push(len);
+        let _ = frame.extend_from_slice(&res[1..1 + len as usize]);
+        frame
+    }
+}
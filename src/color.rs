@@ -0,0 +1,151 @@
+use defmt::Format;
+use num_enum::TryFromPrimitive;
+use rgb::RGB8;
+
+/// Number of gamma table entries sent in one [`crate::cmd::Command::SetGammaTable`]
+pub const GAMMA_CHUNK_SIZE: usize = 15;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive, Format)]
+#[repr(u8)]
+pub enum Gamma {
+    /// Send colors linearly
+    Off = 0,
+    /// Standard gamma of 2.2
+    Gamma22 = 1,
+    /// The table uploaded by the host
+    Custom = 2,
+}
+
+/// `255 * (i / 255) ^ 2.2`, rounded
+#[rustfmt::skip]
+static GAMMA_22: [u8; 256] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2,
+    3, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 6, 6, 6,
+    6, 7, 7, 7, 8, 8, 8, 9, 9, 9, 10, 10, 11, 11, 11, 12,
+    12, 13, 13, 13, 14, 14, 15, 15, 16, 16, 17, 17, 18, 18, 19, 19,
+    20, 20, 21, 22, 22, 23, 23, 24, 25, 25, 26, 26, 27, 28, 28, 29,
+    30, 30, 31, 32, 33, 33, 34, 35, 35, 36, 37, 38, 39, 39, 40, 41,
+    42, 43, 43, 44, 45, 46, 47, 48, 49, 49, 50, 51, 52, 53, 54, 55,
+    56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70, 71,
+    73, 74, 75, 76, 77, 78, 79, 81, 82, 83, 84, 85, 87, 88, 89, 90,
+    91, 93, 94, 95, 97, 98, 99, 100, 102, 103, 105, 106, 107, 109, 110, 111,
+    113, 114, 116, 117, 119, 120, 121, 123, 124, 126, 127, 129, 130, 132, 133, 135,
+    137, 138, 140, 141, 143, 145, 146, 148, 149, 151, 153, 154, 156, 158, 159, 161,
+    163, 165, 166, 168, 170, 172, 173, 175, 177, 179, 181, 182, 184, 186, 188, 190,
+    192, 194, 196, 197, 199, 201, 203, 205, 207, 209, 211, 213, 215, 217, 219, 221,
+    223, 225, 227, 229, 231, 234, 236, 238, 240, 242, 244, 246, 248, 251, 253, 255,
+];
+
+/// Color correction applied between the framebuffer and the wire encoding.
+///
+/// Colors are scaled by the global brightness first, and then gamma corrected,
+/// so dimming stays perceptually even.
+pub struct ColorCorrection {
+    brightness: u8,
+    gamma: Gamma,
+    custom_table: [u8; 256],
+}
+
+impl ColorCorrection {
+    pub fn new() -> Self {
+        let mut custom_table = [0; 256];
+        custom_table.copy_from_slice(&GAMMA_22);
+        Self {
+            brightness: u8::MAX,
+            gamma: Gamma::Gamma22,
+            custom_table,
+        }
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+    }
+
+    pub fn set_gamma(&mut self, gamma: Gamma) {
+        self.gamma = gamma;
+    }
+
+    /// Overwrite part of the custom gamma table. Entries past the end of the table are ignored
+    pub fn set_custom_table(&mut self, offset: usize, data: &[u8]) {
+        let end = (offset + data.len()).min(self.custom_table.len());
+        if offset < end {
+            self.custom_table[offset..end].copy_from_slice(&data[..end - offset]);
+        }
+    }
+
+    fn channel(&self, value: u8) -> u8 {
+        let scaled = ((value as u16 * (self.brightness as u16 + 1)) >> 8) as u8;
+        match self.gamma {
+            Gamma::Off => scaled,
+            Gamma::Gamma22 => GAMMA_22[scaled as usize],
+            Gamma::Custom => self.custom_table[scaled as usize],
+        }
+    }
+
+    pub fn apply(&self, color: RGB8) -> RGB8 {
+        RGB8::new(
+            self.channel(color.r),
+            self.channel(color.g),
+            self.channel(color.b),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gray(v: u8) -> RGB8 {
+        RGB8::new(v, v, v)
+    }
+
+    #[test]
+    fn gamma_table_is_monotonic_and_spans_the_range() {
+        assert_eq!(GAMMA_22[0], 0);
+        assert_eq!(GAMMA_22[255], 255);
+        assert!(GAMMA_22.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn defaults_to_gamma_22_at_full_brightness() {
+        let c = ColorCorrection::new();
+        assert_eq!(
+            c.apply(RGB8::new(0, 128, 255)),
+            RGB8::new(0, GAMMA_22[128], 255)
+        );
+    }
+
+    #[test]
+    fn brightness_scales_before_gamma() {
+        let mut c = ColorCorrection::new();
+        c.set_gamma(Gamma::Off);
+        assert_eq!(c.apply(gray(200)), gray(200));
+        c.set_brightness(127);
+        assert_eq!(c.apply(gray(200)), gray(100));
+        c.set_brightness(0);
+        assert_eq!(c.apply(gray(255)), gray(0));
+
+        c.set_brightness(127);
+        c.set_gamma(Gamma::Gamma22);
+        assert_eq!(c.apply(gray(200)), gray(GAMMA_22[100]));
+    }
+
+    #[test]
+    fn custom_table_upload() {
+        let mut c = ColorCorrection::new();
+        c.set_gamma(Gamma::Custom);
+        // Starts out as a copy of the 2.2 table
+        assert_eq!(c.apply(gray(100)), gray(GAMMA_22[100]));
+
+        c.set_custom_table(10, &[1, 2, 3]);
+        assert_eq!(c.apply(gray(11)), gray(2));
+        assert_eq!(c.apply(gray(13)), gray(GAMMA_22[13]));
+
+        // Clipped at the end of the table
+        c.set_custom_table(254, &[7, 8, 9]);
+        assert_eq!(c.apply(gray(254)), gray(7));
+        assert_eq!(c.apply(gray(255)), gray(8));
+        c.set_custom_table(300, &[1]);
+    }
+}
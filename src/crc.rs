@@ -0,0 +1,54 @@
+/// CRC-8 with polynomial 0x07 and no reflection or final xor, as used by SMBus PEC
+pub const fn update(crc: u8, byte: u8) -> u8 {
+    let mut crc = crc ^ byte;
+    let mut i = 0;
+    while i < 8 {
+        crc = if crc & 0x80 != 0 {
+            (crc << 1) ^ 0x07
+        } else {
+            crc << 1
+        };
+        i += 1;
+    }
+    crc
+}
+
+/// Continue a CRC over `data`. Start with 0
+pub fn crc8(crc: u8, data: &[u8]) -> u8 {
+    data.iter().fold(crc, |crc, b| update(crc, *b))
+}
+
+/// SMBus PEC of a transfer: the CRC covers the address byte, including the R/W bit,
+/// followed by the data
+pub fn pec(address: u8, read: bool, data: &[u8]) -> u8 {
+    crc8(update(0, address << 1 | read as u8), data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc8(0, b"123456789"), 0xF4);
+    }
+
+    #[test]
+    fn continues() {
+        assert_eq!(crc8(crc8(0, b"1234"), b"56789"), 0xF4);
+    }
+
+    #[test]
+    fn appending_crc_gives_zero() {
+        let data = [0x77 << 1, 0x01, 0x04, 3, 0xFF, 0x00, 0x80];
+        let crc = crc8(0, &data);
+        assert_eq!(update(crc, crc), 0);
+    }
+
+    #[test]
+    fn pec_includes_address() {
+        let data = [0x11, 0x00];
+        assert_eq!(pec(0x77, false, &data), crc8(0, &[0xEE, 0x11, 0x00]));
+        assert_eq!(pec(0x77, true, &data), crc8(0, &[0xEF, 0x11, 0x00]));
+    }
+}
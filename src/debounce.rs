@@ -0,0 +1,137 @@
+/// Per-key integrating debouncer.
+///
+/// Each key has a counter that integrates the time its raw input has been high,
+/// and counts back down while it is low. The debounced state only changes once the
+/// counter hits either end, i.e. after the input has been (mostly) stable for the
+/// whole window. Works with any scan period, as the elapsed time is passed to every update.
+pub struct Debouncer<const N: usize> {
+    /// Integrated time in ms, between 0 and the key's window
+    counters: [u16; N],
+    /// Debounce window in ms
+    windows: [u16; N],
+    states: [bool; N],
+}
+
+/// Default debounce window in ms
+pub const DEFAULT_WINDOW_MS: u16 = 5;
+
+impl<const N: usize> Debouncer<N> {
+    pub fn new(window_ms: u16) -> Self {
+        Self {
+            counters: [0; N],
+            windows: [window_ms; N],
+            states: [false; N],
+        }
+    }
+
+    /// Set the debounce window of a single key
+    pub fn set_window(&mut self, idx: usize, window_ms: u16) {
+        self.windows[idx] = window_ms;
+        self.counters[idx] = if self.states[idx] { window_ms } else { 0 };
+    }
+
+    /// The debounced state of a key
+    pub fn get(&self, idx: usize) -> bool {
+        self.states[idx]
+    }
+
+    /// Feed a raw sample for a key, taken `elapsed_ms` after the previous one.
+    /// Returns true if the debounced state changed
+    pub fn update(&mut self, idx: usize, raw: bool, elapsed_ms: u16) -> bool {
+        let window = self.windows[idx];
+        let counter = &mut self.counters[idx];
+        if raw {
+            *counter = counter.saturating_add(elapsed_ms).min(window);
+        } else {
+            *counter = counter.saturating_sub(elapsed_ms);
+        }
+
+        let old = self.states[idx];
+        if raw && *counter >= window {
+            self.states[idx] = true;
+        } else if !raw && *counter == 0 {
+            self.states[idx] = false;
+        }
+        self.states[idx] != old
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feed a trace of raw samples taken every `period` ms, and return the
+    /// sample indices at which the debounced state changed
+    fn run(debouncer: &mut Debouncer<1>, period: u16, trace: &str) -> ([usize; 8], usize) {
+        let mut changes = [0; 8];
+        let mut n = 0;
+        for (i, c) in trace.chars().enumerate() {
+            if debouncer.update(0, c == '1', period) {
+                changes[n] = i;
+                n += 1;
+            }
+        }
+        (changes, n)
+    }
+
+    #[test]
+    fn clean_press_and_release() {
+        let mut d = Debouncer::<1>::new(5);
+        let (changes, n) = run(&mut d, 1, "0001111111111000000000");
+        assert_eq!(&changes[..n], &[7, 17]);
+        assert!(!d.get(0));
+    }
+
+    #[test]
+    fn bouncy_press_is_reported_once() {
+        let mut d = Debouncer::<1>::new(5);
+        let (changes, n) = run(&mut d, 1, "0010110111111111011010100000000");
+        assert_eq!(n, 2);
+        assert!(changes[0] > 2 && changes[0] < 16);
+        assert!(changes[1] > 16);
+        assert!(!d.get(0));
+    }
+
+    #[test]
+    fn short_glitch_is_ignored() {
+        let mut d = Debouncer::<1>::new(5);
+        let (_, n) = run(&mut d, 1, "00000110000000010000");
+        assert_eq!(n, 0);
+        assert!(!d.get(0));
+
+        let (_, n) = run(&mut d, 1, "1111111111");
+        assert_eq!(n, 1);
+        let (_, n) = run(&mut d, 1, "111100111101111111");
+        assert_eq!(n, 0);
+        assert!(d.get(0));
+    }
+
+    #[test]
+    fn slow_scans_follow_raw_state() {
+        let mut d = Debouncer::<1>::new(5);
+        let (changes, n) = run(&mut d, 10, "0110");
+        assert_eq!(&changes[..n], &[1, 3]);
+    }
+
+    #[test]
+    fn per_key_windows() {
+        let mut d = Debouncer::<2>::new(2);
+        d.set_window(1, 8);
+        let mut first = [None; 2];
+        for i in 0..10 {
+            for k in 0..2 {
+                if d.update(k, true, 1) && first[k].is_none() {
+                    first[k] = Some(i);
+                }
+            }
+        }
+        assert_eq!(first, [Some(1), Some(7)]);
+    }
+
+    #[test]
+    fn zero_window_disables_debouncing() {
+        let mut d = Debouncer::<1>::new(0);
+        let (changes, n) = run(&mut d, 1, "0101");
+        assert_eq!(&changes[..n], &[1, 2, 3]);
+    }
+}
@@ -0,0 +1,316 @@
+use rgb::RGB8;
+
+use crate::leds::NUM_LEDS;
+
+/// Maximum number of effects running at the same time
+pub const MAX_EFFECTS: usize = 16;
+
+/// An animation running on a range of LEDs
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Effect {
+    Solid(RGB8),
+    /// On for `duty / 256` of every period, off for the rest
+    Blink {
+        color: RGB8,
+        period_ms: u16,
+        duty: u8,
+    },
+    /// Fade in and out again once per period
+    Pulse {
+        color: RGB8,
+        period_ms: u16,
+    },
+    /// Fade linearly from the color each LED had when the effect started.
+    /// Stays at `color` once done
+    Fade {
+        color: RGB8,
+        duration_ms: u16,
+    },
+    /// A single lit LED moving through the range once per period
+    Chase {
+        color: RGB8,
+        period_ms: u16,
+    },
+    /// A color wheel spread over the range, rotating once per period
+    Rainbow {
+        period_ms: u16,
+    },
+}
+
+fn scale(color: RGB8, level: u8) -> RGB8 {
+    let s = |c: u8| ((c as u16 * (level as u16 + 1)) >> 8) as u8;
+    RGB8::new(s(color.r), s(color.g), s(color.b))
+}
+
+fn lerp(from: u8, to: u8, pos: u32, len: u32) -> u8 {
+    (from as i32 + (to as i32 - from as i32) * pos as i32 / len as i32) as u8
+}
+
+/// Fully saturated color for a position on the color wheel
+fn wheel(hue: u8) -> RGB8 {
+    let section = hue / 86;
+    let pos = (hue % 86) * 3;
+    match section {
+        0 => RGB8::new(255 - pos, pos, 0),
+        1 => RGB8::new(0, 255 - pos, pos),
+        _ => RGB8::new(pos, 0, 255 - pos),
+    }
+}
+
+/// Evaluate an effect for the LED at `pos` of a range of `len` LEDs,
+/// `elapsed_ms` after it started. `base` is the color the LED had when the effect started
+pub fn eval(effect: &Effect, elapsed_ms: u32, pos: usize, len: usize, base: RGB8) -> RGB8 {
+    let phase = |period_ms: u16| {
+        let period = period_ms.max(1) as u32;
+        (elapsed_ms % period, period)
+    };
+    match *effect {
+        Effect::Solid(color) => color,
+        Effect::Blink {
+            color,
+            period_ms,
+            duty,
+        } => {
+            let (t, period) = phase(period_ms);
+            if t * 256 < duty as u32 * period {
+                color
+            } else {
+                RGB8::default()
+            }
+        }
+        Effect::Pulse { color, period_ms } => {
+            let (t, period) = phase(period_ms);
+            // Triangle wave, 0 to 255 and back
+            let level = (t * 510 / period) as u16;
+            let level = if level > 255 { 510 - level } else { level };
+            scale(color, level as u8)
+        }
+        Effect::Fade { color, duration_ms } => {
+            let duration = duration_ms as u32;
+            if elapsed_ms >= duration {
+                return color;
+            }
+            RGB8::new(
+                lerp(base.r, color.r, elapsed_ms, duration),
+                lerp(base.g, color.g, elapsed_ms, duration),
+                lerp(base.b, color.b, elapsed_ms, duration),
+            )
+        }
+        Effect::Chase { color, period_ms } => {
+            let (t, period) = phase(period_ms);
+            let lit = (t * len as u32 / period) as usize;
+            if pos == lit {
+                color
+            } else {
+                RGB8::default()
+            }
+        }
+        Effect::Rainbow { period_ms } => {
+            let (t, period) = phase(period_ms);
+            let offset = t * 256 / period + (pos * 256 / len.max(1)) as u32;
+            wheel(offset as u8)
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Running {
+    effect: Effect,
+    start: u8,
+    end: u8,
+    started_ms: u32,
+}
+
+/// The effects running on the strip, and which LEDs they control.
+/// Each LED is controlled by at most one effect, the one started last
+pub struct Effects {
+    slots: [Option<Running>; MAX_EFFECTS],
+    owners: [Option<u8>; NUM_LEDS],
+    /// The colors at the start of each LED's effect
+    bases: [RGB8; NUM_LEDS],
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, defmt::Format)]
+pub struct TooManyEffects;
+
+impl Effects {
+    pub fn new() -> Self {
+        Self {
+            slots: [None; MAX_EFFECTS],
+            owners: [None; NUM_LEDS],
+            bases: [RGB8::default(); NUM_LEDS],
+        }
+    }
+
+    /// Free slots that no longer control any LED
+    fn collect_unused(&mut self) {
+        for (i, slot) in self.slots.iter_mut().enumerate() {
+            if !self.owners.contains(&Some(i as u8)) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Start an effect on LEDs `start..end`, replacing whatever effects they had.
+    /// `current` are the colors currently in the framebuffer
+    pub fn start(
+        &mut self,
+        start: u8,
+        end: u8,
+        effect: Effect,
+        now_ms: u32,
+        current: &[RGB8; NUM_LEDS],
+    ) -> Result<(), TooManyEffects> {
+        let (s, e) = (start as usize, (end as usize).min(NUM_LEDS));
+        // Find a slot before cancelling anything, so a failed start leaves the running
+        // effects alone. A slot is also usable if the cancel frees it
+        let freed = |slot: usize| {
+            let mut owners = self.owners.iter().enumerate();
+            owners.all(|(led, o)| *o != Some(slot as u8) || (s..e).contains(&led))
+        };
+        let idx = (0..MAX_EFFECTS)
+            .find(|&i| self.slots[i].is_none() || freed(i))
+            .ok_or(TooManyEffects)?;
+        self.cancel(s, e);
+        self.slots[idx] = Some(Running {
+            effect,
+            start,
+            end: e as u8,
+            started_ms: now_ms,
+        });
+        for i in s..e {
+            self.owners[i] = Some(idx as u8);
+            self.bases[i] = current[i];
+        }
+        Ok(())
+    }
+
+    /// Stop all effects on LEDs `start..end`, leaving them at their current color
+    pub fn cancel(&mut self, start: usize, end: usize) {
+        let end = end.min(NUM_LEDS);
+        for owner in self.owners[start.min(end)..end].iter_mut() {
+            *owner = None;
+        }
+        self.collect_unused();
+    }
+
+    /// Evaluate all effects at `now_ms` into `pixels`
+    pub fn render(&self, now_ms: u32, pixels: &mut [RGB8; NUM_LEDS]) {
+        for (i, owner) in self.owners.iter().enumerate() {
+            if let Some(Some(r)) = owner.map(|o| &self.slots[o as usize]) {
+                let elapsed = now_ms.wrapping_sub(r.started_ms);
+                let len = (r.end - r.start) as usize;
+                pixels[i] = eval(&r.effect, elapsed, i - r.start as usize, len, self.bases[i]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: RGB8 = RGB8::new(255, 0, 0);
+    const BLACK: RGB8 = RGB8::new(0, 0, 0);
+
+    #[test]
+    fn blink_follows_duty_cycle() {
+        let e = Effect::Blink {
+            color: RED,
+            period_ms: 1000,
+            duty: 64,
+        };
+        assert_eq!(eval(&e, 0, 0, 1, BLACK), RED);
+        assert_eq!(eval(&e, 249, 0, 1, BLACK), RED);
+        assert_eq!(eval(&e, 250, 0, 1, BLACK), BLACK);
+        assert_eq!(eval(&e, 999, 0, 1, BLACK), BLACK);
+        assert_eq!(eval(&e, 1000, 0, 1, BLACK), RED);
+    }
+
+    #[test]
+    fn pulse_peaks_mid_period() {
+        let e = Effect::Pulse {
+            color: RED,
+            period_ms: 100,
+        };
+        assert_eq!(eval(&e, 0, 0, 1, BLACK), BLACK);
+        assert_eq!(eval(&e, 50, 0, 1, BLACK), RED);
+        assert!(eval(&e, 25, 0, 1, BLACK).r > 100);
+        assert!(eval(&e, 25, 0, 1, BLACK).r < 155);
+        let (up, down) = (eval(&e, 25, 0, 1, BLACK).r, eval(&e, 75, 0, 1, BLACK).r);
+        assert!((up as i16 - down as i16).abs() <= 1);
+    }
+
+    #[test]
+    fn fade_is_linear_and_holds_target() {
+        let e = Effect::Fade {
+            color: RGB8::new(200, 0, 100),
+            duration_ms: 100,
+        };
+        let base = RGB8::new(0, 100, 100);
+        assert_eq!(eval(&e, 0, 0, 1, base), base);
+        assert_eq!(eval(&e, 50, 0, 1, base), RGB8::new(100, 50, 100));
+        assert_eq!(eval(&e, 100, 0, 1, base), RGB8::new(200, 0, 100));
+        assert_eq!(eval(&e, 5000, 0, 1, base), RGB8::new(200, 0, 100));
+    }
+
+    #[test]
+    fn chase_lights_one_led_at_a_time() {
+        let e = Effect::Chase {
+            color: RED,
+            period_ms: 400,
+        };
+        for t in [0, 100, 200, 300] {
+            let lit: usize = (0..4).filter(|&p| eval(&e, t, p, 4, BLACK) == RED).count();
+            assert_eq!(lit, 1);
+            assert_eq!(eval(&e, t, t as usize / 100, 4, BLACK), RED);
+        }
+    }
+
+    #[test]
+    fn rainbow_rotates() {
+        let e = Effect::Rainbow { period_ms: 256 };
+        assert_eq!(eval(&e, 0, 0, 3, BLACK), RED);
+        assert_eq!(eval(&e, 0, 1, 3, BLACK), eval(&e, 85, 0, 3, BLACK));
+        assert_eq!(eval(&e, 256, 0, 3, BLACK), RED);
+    }
+
+    #[test]
+    fn later_effects_take_over_leds() {
+        let mut fx = Effects::new();
+        let mut px = [BLACK; NUM_LEDS];
+        fx.start(0, 10, Effect::Solid(RED), 0, &px).unwrap();
+        fx.start(5, 6, Effect::Solid(BLACK), 0, &px).unwrap();
+        fx.render(0, &mut px);
+        assert_eq!(px[4], RED);
+        assert_eq!(px[5], BLACK);
+        assert_eq!(px[6], RED);
+        assert_eq!(px[10], BLACK);
+
+        fx.cancel(0, 10);
+        assert!(fx.slots.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn too_many_effects_keeps_running_ones() {
+        let mut fx = Effects::new();
+        let mut px = [BLACK; NUM_LEDS];
+        for i in 0..MAX_EFFECTS as u8 {
+            fx.start(2 * i, 2 * i + 2, Effect::Solid(RED), 0, &px)
+                .unwrap();
+        }
+        // Only covers parts of two effects, which keep running
+        assert_eq!(
+            fx.start(1, 3, Effect::Solid(BLACK), 0, &px),
+            Err(TooManyEffects)
+        );
+        fx.render(0, &mut px);
+        assert!(px[..2 * MAX_EFFECTS].iter().all(|p| *p == RED));
+
+        // Replacing an effect entirely reuses its slot
+        fx.start(0, 2, Effect::Solid(BLACK), 0, &px).unwrap();
+        fx.render(0, &mut px);
+        assert_eq!(px[1], BLACK);
+        assert_eq!(px[2], RED);
+    }
+}
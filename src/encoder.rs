@@ -0,0 +1,356 @@
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicI32, Ordering};
+
+use cortex_m::interrupt::Mutex;
+use defmt::{trace, Format};
+use embassy::time::{Duration, Instant, Timer};
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::AnyPin;
+use embassy_traits::gpio::WaitForAnyEdge;
+use embedded_hal::digital::v2::InputPin;
+use futures::future::{select, Either};
+use futures::pin_mut;
+use num_enum::TryFromPrimitive;
+
+use crate::cmd::{self, Message};
+use crate::input::EventSender;
+
+pub const NUM_ENCODERS: usize = 4;
+
+/// How often steps that didn't fit into the event channel are sent again
+const RETRY_PERIOD: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive, Format)]
+#[repr(u8)]
+pub enum EncoderId {
+    Blue = 0,
+    Green = 1,
+    Yellow = 2,
+    Red = 3,
+}
+
+/// Quadrature state machine turning A/B levels into detent steps.
+///
+/// The encoders on the OTTO front panel have one detent per full quadrature cycle,
+/// resting with both A and B high. Single transitions are accumulated as quarter steps,
+/// and only turned into a step once the encoder reaches a detent position. This filters
+/// out contact bounce, and tolerates a missed transition when turning fast.
+pub struct Quadrature {
+    state: u8,
+    quarter_steps: i8,
+}
+
+impl Quadrature {
+    pub fn new(a: bool, b: bool) -> Self {
+        Self {
+            state: Self::state_of(a, b),
+            quarter_steps: 0,
+        }
+    }
+
+    fn state_of(a: bool, b: bool) -> u8 {
+        (a as u8) << 1 | b as u8
+    }
+
+    /// Gray code transition table, indexed by `old_state << 2 | new_state`.
+    /// Zero for no change or an invalid (skipped) transition.
+    const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+    /// Feed the current levels of A and B; returns the number of steps taken (-1, 0 or 1)
+    pub fn update(&mut self, a: bool, b: bool) -> i8 {
+        let new = Self::state_of(a, b);
+        self.quarter_steps += Self::TRANSITIONS[(self.state << 2 | new) as usize];
+        self.state = new;
+
+        if new != 0b11 {
+            return 0;
+        }
+        let q = core::mem::take(&mut self.quarter_steps);
+        if q >= 2 {
+            1
+        } else if q <= -2 {
+            -1
+        } else {
+            0
+        }
+    }
+}
+
+/// Number of speed buckets in [`Curve::Table`]
+pub const CURVE_TABLE_SIZE: usize = 8;
+/// Width of a [`Curve::Table`] bucket in detents per second
+pub const CURVE_TABLE_STEP: u16 = 8;
+/// Upper limit for the step multiplier of any curve
+pub const MAX_MULTIPLIER: u16 = 32;
+
+/// Step multiplier as a function of turning speed, in detents per second
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum Curve {
+    /// `1 + (speed - threshold) * gain / 64`
+    Linear { threshold: u8, gain: u8 },
+    /// Doubles for every `doubling` detents/s above `threshold`
+    Exponential { threshold: u8, doubling: u8 },
+    /// One multiplier per speed bucket of [`CURVE_TABLE_STEP`] detents/s.
+    /// Speeds beyond the last bucket use the last entry
+    Table([u8; CURVE_TABLE_SIZE]),
+}
+
+impl Curve {
+    pub fn multiplier(&self, speed: u16) -> u16 {
+        let m = match *self {
+            Curve::Linear { threshold, gain } => {
+                1 + speed.saturating_sub(threshold as u16) as u32 * gain as u32 / 64
+            }
+            Curve::Exponential {
+                threshold,
+                doubling,
+            } => {
+                let over = speed.saturating_sub(threshold as u16) as u32;
+                1u32.checked_shl(over / (doubling.max(1) as u32))
+                    .unwrap_or(u32::MAX)
+            }
+            Curve::Table(table) => {
+                let bucket = ((speed / CURVE_TABLE_STEP) as usize).min(CURVE_TABLE_SIZE - 1);
+                table[bucket].max(1) as u32
+            }
+        };
+        m.min(MAX_MULTIPLIER as u32) as u16
+    }
+}
+
+/// Velocity based step multiplication.
+///
+/// The speed is estimated from the time between detents, and smoothed over
+/// consecutive steps. Changing direction starts over at zero speed.
+pub struct Acceleration {
+    last_step: Option<(u32, i8)>,
+    /// Detents per second
+    speed: u16,
+}
+
+impl Acceleration {
+    pub fn new() -> Self {
+        Self {
+            last_step: None,
+            speed: 0,
+        }
+    }
+
+    /// Apply `curve` to a step taken at `now_ms`. Without a curve, steps are passed through
+    pub fn apply(&mut self, curve: Option<&Curve>, steps: i8, now_ms: u32) -> i16 {
+        self.speed = match self.last_step {
+            Some((t, dir)) if dir == steps.signum() => {
+                let instant_speed = (1000 / now_ms.wrapping_sub(t).max(1)).min(u16::MAX as u32);
+                ((self.speed as u32 + instant_speed) / 2) as u16
+            }
+            _ => 0,
+        };
+        self.last_step = Some((now_ms, steps.signum()));
+        match curve {
+            Some(curve) => steps as i16 * curve.multiplier(self.speed) as i16,
+            None => steps as i16,
+        }
+    }
+}
+
+/// Acceleration curves per encoder, set by the host
+static CURVES: Mutex<RefCell<[Option<Curve>; NUM_ENCODERS]>> =
+    Mutex::new(RefCell::new([None; NUM_ENCODERS]));
+
+/// Enable acceleration for an encoder, or disable it with `None`
+pub fn set_acceleration(id: EncoderId, curve: Option<Curve>) {
+    cortex_m::interrupt::free(|cs| CURVES.borrow(cs).borrow_mut()[id as usize] = curve);
+}
+
+fn acceleration(id: EncoderId) -> Option<Curve> {
+    cortex_m::interrupt::free(|cs| CURVES.borrow(cs).borrow()[id as usize])
+}
+
+/// Absolute positions of all encoders, in steps since boot. These are the steps sent in
+/// [`Message::Encoder`], so they include acceleration and aren't detents
+static POSITIONS: [AtomicI32; NUM_ENCODERS] = [
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+    AtomicI32::new(0),
+];
+
+/// The absolute position of an encoder
+pub fn position(id: EncoderId) -> i32 {
+    POSITIONS[id as usize].load(Ordering::Relaxed)
+}
+
+/// The absolute positions of all encoders, in [`EncoderId`] order
+pub fn positions() -> [i32; NUM_ENCODERS] {
+    [
+        position(EncoderId::Blue),
+        position(EncoderId::Green),
+        position(EncoderId::Yellow),
+        position(EncoderId::Red),
+    ]
+}
+
+pub struct Encoder {
+    pub id: EncoderId,
+    pub a: ExtiInput<'static, AnyPin>,
+    pub b: ExtiInput<'static, AnyPin>,
+    decoder: Quadrature,
+}
+
+impl Encoder {
+    pub fn new(
+        id: EncoderId,
+        a: ExtiInput<'static, AnyPin>,
+        b: ExtiInput<'static, AnyPin>,
+    ) -> Self {
+        let decoder = Quadrature::new(a.is_high().unwrap(), b.is_high().unwrap());
+        Self { id, a, b, decoder }
+    }
+
+    /// Wait for an edge on either A or B, and decode it.
+    /// Returns the steps taken and the time of the edge
+    pub async fn next_steps(&mut self) -> (i8, Instant) {
+        {
+            let a = self.a.wait_for_any_edge();
+            let b = self.b.wait_for_any_edge();
+            pin_mut!(a, b);
+            select(a, b).await;
+        }
+        let now = Instant::now();
+        let a = self.a.is_high().unwrap();
+        let b = self.b.is_high().unwrap();
+        (self.decoder.update(a, b), now)
+    }
+}
+
+#[embassy::task(pool_size = 4)]
+pub async fn poll_encoder(mut encoder: Encoder, events: EventSender) {
+    // Steps that could not be sent yet, because the event channel was full,
+    // and the time of the last of them
+    let mut pending: i16 = 0;
+    let mut pending_time = Instant::now();
+    let mut accel = Acceleration::new();
+    loop {
+        let (steps, now) = if pending == 0 {
+            encoder.next_steps().await
+        } else {
+            // Don't leave the pending steps until the encoder moves again
+            let next = encoder.next_steps();
+            let retry = Timer::after(RETRY_PERIOD);
+            pin_mut!(next);
+            match select(next, retry).await {
+                Either::Left((next, _)) => next,
+                Either::Right(_) => (0, pending_time),
+            }
+        };
+        if steps != 0 {
+            let curve = acceleration(encoder.id);
+            let now_ms = now.as_millis() as u32;
+            let steps = accel.apply(curve.as_ref(), steps, now_ms);
+            POSITIONS[encoder.id as usize].fetch_add(steps as i32, Ordering::Relaxed);
+            pending = pending.saturating_add(steps);
+            pending_time = now;
+        }
+        if pending == 0 {
+            continue;
+        }
+        let steps = pending.clamp(i8::MIN as i16, i8::MAX as i16) as i8;
+        trace!("Encoder {}: {}", encoder.id, steps);
+        let event = Message::Encoder {
+            encoder: encoder.id,
+            steps,
+            time: cmd::timestamp(pending_time),
+        };
+        if events.try_send(event).is_ok() {
+            pending -= steps as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Levels of A and B through one cycle from the detent, counting up and down
+    const UP: [(bool, bool); 4] = [(false, true), (false, false), (true, false), (true, true)];
+    const DOWN: [(bool, bool); 4] = [(true, false), (false, false), (false, true), (true, true)];
+
+    fn feed(q: &mut Quadrature, levels: [(bool, bool); 4]) -> [i8; 4] {
+        levels.map(|(a, b)| q.update(a, b))
+    }
+
+    #[test]
+    fn full_cycles_step_once_per_detent() {
+        let mut q = Quadrature::new(true, true);
+        assert_eq!(feed(&mut q, UP), [0, 0, 0, 1]);
+        assert_eq!(feed(&mut q, UP), [0, 0, 0, 1]);
+        assert_eq!(feed(&mut q, DOWN), [0, 0, 0, -1]);
+        assert_eq!(feed(&mut q, DOWN), [0, 0, 0, -1]);
+    }
+
+    #[test]
+    fn bounce_at_detent_is_ignored() {
+        let mut q = Quadrature::new(true, true);
+        let bounce = [(false, true), (true, true), (false, true), (true, true)];
+        assert_eq!(feed(&mut q, bounce), [0; 4]);
+        assert_eq!(feed(&mut q, UP), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn reversing_mid_cycle_takes_no_step() {
+        let mut q = Quadrature::new(true, true);
+        let back = [(false, true), (false, false), (false, true), (true, true)];
+        assert_eq!(feed(&mut q, back), [0; 4]);
+        assert_eq!(feed(&mut q, DOWN), [0, 0, 0, -1]);
+        assert_eq!(feed(&mut q, UP), [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn linear_curve() {
+        let c = Curve::Linear {
+            threshold: 10,
+            gain: 64,
+        };
+        assert_eq!(c.multiplier(0), 1);
+        assert_eq!(c.multiplier(10), 1);
+        assert_eq!(c.multiplier(13), 4);
+        assert_eq!(c.multiplier(1000), MAX_MULTIPLIER);
+    }
+
+    #[test]
+    fn exponential_curve() {
+        let c = Curve::Exponential {
+            threshold: 10,
+            doubling: 5,
+        };
+        assert_eq!(c.multiplier(10), 1);
+        assert_eq!(c.multiplier(15), 2);
+        assert_eq!(c.multiplier(24), 4);
+        assert_eq!(c.multiplier(u16::MAX), MAX_MULTIPLIER);
+    }
+
+    #[test]
+    fn table_curve() {
+        let c = Curve::Table([0, 2, 3, 4, 5, 6, 7, 200]);
+        assert_eq!(c.multiplier(0), 1);
+        assert_eq!(c.multiplier(CURVE_TABLE_STEP), 2);
+        assert_eq!(c.multiplier(2 * CURVE_TABLE_STEP - 1), 2);
+        assert_eq!(c.multiplier(1000), MAX_MULTIPLIER);
+    }
+
+    #[test]
+    fn acceleration_restarts_on_direction_change() {
+        // Multiplier is 1 + speed
+        let curve = Curve::Linear {
+            threshold: 0,
+            gain: 64,
+        };
+        let mut accel = Acceleration::new();
+        assert_eq!(accel.apply(Some(&curve), 1, 0), 1);
+        // 10 detents/s, smoothed with the previous speed of 0
+        assert_eq!(accel.apply(Some(&curve), 1, 100), 6);
+        assert_eq!(accel.apply(Some(&curve), 1, 200), 8);
+        assert_eq!(accel.apply(Some(&curve), -1, 300), -1);
+        assert_eq!(accel.apply(None, -1, 310), -1);
+    }
+}
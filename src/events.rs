@@ -0,0 +1,132 @@
+use heapless::spsc::Queue;
+
+/// An item taken out of an [`EventQueue`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Popped<T> {
+    Event(T),
+    /// Marker for events that were dropped because the queue was full
+    Lost(u16),
+}
+
+/// Fixed size event queue that keeps track of overflows.
+///
+/// Once an event has been dropped, all following events are dropped as well,
+/// until the queue has been drained and the [`Popped::Lost`] marker has been popped.
+/// This way the consumer sees everything up to the overflow, followed by the marker,
+/// and knows it has to re-sync its state.
+///
+/// Holds up to `N - 1` events.
+pub struct EventQueue<T, const N: usize> {
+    queue: Queue<T, N>,
+    /// Events dropped since the last marker was popped
+    lost: u16,
+    /// Total number of dropped events
+    overflows: u32,
+}
+
+impl<T, const N: usize> EventQueue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Queue::new(),
+            lost: 0,
+            overflows: 0,
+        }
+    }
+
+    /// Returns the event as Err if it had to be dropped
+    pub fn push(&mut self, event: T) -> Result<(), T> {
+        if self.lost > 0 {
+            self.drop_event();
+            return Err(event);
+        }
+        self.queue.enqueue(event).map_err(|e| {
+            self.drop_event();
+            e
+        })
+    }
+
+    fn drop_event(&mut self) {
+        self.lost = self.lost.saturating_add(1);
+        self.overflows = self.overflows.saturating_add(1);
+    }
+
+    pub fn pop(&mut self) -> Option<Popped<T>> {
+        if let Some(event) = self.queue.dequeue() {
+            return Some(Popped::Event(event));
+        }
+        if self.lost > 0 {
+            return Some(Popped::Lost(core::mem::take(&mut self.lost)));
+        }
+        None
+    }
+
+    /// True if there is nothing left to pop, including the overflow marker
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty() && self.lost == 0
+    }
+
+    /// Total number of events dropped since creation or the last [`Self::clear_overflows`]
+    pub fn overflows(&self) -> u32 {
+        self.overflows
+    }
+
+    pub fn clear_overflows(&mut self) {
+        self.overflows = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fifo_order() {
+        let mut q = EventQueue::<u8, 4>::new();
+        assert!(q.is_empty());
+        q.push(1).unwrap();
+        q.push(2).unwrap();
+        assert_eq!(q.pop(), Some(Popped::Event(1)));
+        assert_eq!(q.pop(), Some(Popped::Event(2)));
+        assert_eq!(q.pop(), None);
+        assert_eq!(q.overflows(), 0);
+    }
+
+    #[test]
+    fn overflow_reports_marker_after_queued_events() {
+        let mut q = EventQueue::<u8, 4>::new();
+        for i in 0..3 {
+            q.push(i).unwrap();
+        }
+        assert_eq!(q.push(3), Err(3));
+        assert_eq!(q.push(4), Err(4));
+        assert_eq!(q.overflows(), 2);
+
+        assert_eq!(q.pop(), Some(Popped::Event(0)));
+        // Still dropping until the marker is delivered, even though there is room again
+        assert_eq!(q.push(5), Err(5));
+        assert_eq!(q.pop(), Some(Popped::Event(1)));
+        assert_eq!(q.pop(), Some(Popped::Event(2)));
+        assert!(!q.is_empty());
+        assert_eq!(q.pop(), Some(Popped::Lost(3)));
+        assert!(q.is_empty());
+        assert_eq!(q.pop(), None);
+
+        q.push(6).unwrap();
+        assert_eq!(q.pop(), Some(Popped::Event(6)));
+        assert_eq!(q.overflows(), 3);
+        q.clear_overflows();
+        assert_eq!(q.overflows(), 0);
+    }
+
+    #[test]
+    fn lost_count_saturates() {
+        let mut q = EventQueue::<u8, 2>::new();
+        q.push(0).unwrap();
+        for _ in 0..70_000 {
+            assert!(q.push(1).is_err());
+        }
+        assert_eq!(q.pop(), Some(Popped::Event(0)));
+        assert_eq!(q.pop(), Some(Popped::Lost(u16::MAX)));
+        assert_eq!(q.overflows(), 70_000);
+    }
+}
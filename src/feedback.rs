@@ -0,0 +1,86 @@
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use rgb::RGB8;
+
+use crate::input::Key;
+use crate::ledmap;
+use crate::leds::NUM_LEDS;
+use crate::util::bitarray::BitArray;
+
+/// Local key feedback: light up the LED of a held key without waiting for the host.
+///
+/// Written by the input task, read by the LED task which draws it over the framebuffer.
+struct Feedback {
+    enabled: bool,
+    color: RGB8,
+    /// Indexed by `Key as u8`
+    disabled_keys: BitArray<64>,
+    /// Indexed by `Key as u8`
+    held: BitArray<64>,
+    changed: bool,
+}
+
+static FEEDBACK: Mutex<RefCell<Feedback>> = Mutex::new(RefCell::new(Feedback {
+    enabled: false,
+    color: RGB8 {
+        r: 0xFF,
+        g: 0xFF,
+        b: 0xFF,
+    },
+    disabled_keys: BitArray::new(),
+    held: BitArray::new(),
+    changed: false,
+}));
+
+fn with<R>(f: impl FnOnce(&mut Feedback) -> R) -> R {
+    cortex_m::interrupt::free(|cs| f(&mut FEEDBACK.borrow(cs).borrow_mut()))
+}
+
+/// Enable or disable feedback globally, and set the overlay color
+pub fn set_enabled(enabled: bool, color: RGB8) {
+    with(|fb| {
+        fb.enabled = enabled;
+        fb.color = color;
+        fb.changed = true;
+    })
+}
+
+/// Enable or disable feedback for a single key
+pub fn set_key_enabled(key: Key, enabled: bool) {
+    with(|fb| {
+        fb.disabled_keys.set(key as usize, !enabled);
+        fb.changed = true;
+    })
+}
+
+/// Called by the input task on every press and release
+pub fn set_held(key: Key, held: bool) {
+    with(|fb| {
+        fb.held.set(key as usize, held);
+        fb.changed |= fb.enabled;
+    })
+}
+
+/// Returns true if the overlay changed since the last call
+pub fn take_changed() -> bool {
+    with(|fb| core::mem::take(&mut fb.changed))
+}
+
+/// Draw the feedback overlay over `pixels`
+pub fn overlay(pixels: &mut [RGB8; NUM_LEDS]) {
+    with(|fb| {
+        if !fb.enabled {
+            return;
+        }
+        for k in 0..64 {
+            if !fb.held.get(k) || fb.disabled_keys.get(k) {
+                continue;
+            }
+            let led = Key::try_from(k as u8).ok().and_then(ledmap::led_of_key);
+            if let Some(led) = led {
+                pixels[led as usize] = fb.color;
+            }
+        }
+    })
+}
@@ -0,0 +1,262 @@
+use defmt::{error, Format};
+use embassy::channel::mpsc::{Receiver, Sender, WithNoThreads};
+use embassy::time::{Duration, Instant, Timer};
+use rgb::RGB8;
+
+use crate::cmd::{ErrorCode, Message};
+use crate::color::{ColorCorrection, Gamma, GAMMA_CHUNK_SIZE};
+use crate::effects::{Effect, Effects};
+use crate::feedback;
+use crate::input::EventSender;
+use crate::leds::{Leds, NUM_LEDS};
+use crate::power;
+
+/// Time between frames pushed to the strip
+pub const REFRESH_PERIOD: Duration = Duration::from_millis(20);
+
+/// Number of LED commands that can be waiting for the next frame
+pub const LED_CHANNEL_SIZE: usize = 16;
+
+/// Colors in one [`LedCommand::SetLeds`]. Longer updates are split, which keeps the
+/// commands, and so the channel, small
+pub const LEDS_PER_COMMAND: usize = 8;
+
+/// Commands are sent with the command byte they came from, to report errors to the host
+pub type LedSender = Sender<'static, WithNoThreads, (u8, LedCommand), LED_CHANNEL_SIZE>;
+pub type LedReceiver = Receiver<'static, WithNoThreads, (u8, LedCommand), LED_CHANNEL_SIZE>;
+
+/// Updates to the framebuffer, sent to the LED task.
+/// Setting colors directly stops any effects on those LEDs
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LedCommand {
+    Set {
+        idx: u8,
+        color: RGB8,
+    },
+    /// `end` is exclusive
+    SetRange {
+        start: u8,
+        end: u8,
+        color: RGB8,
+    },
+    SetAll(RGB8),
+    /// Set `len` LEDs starting at `start` to the first `len` colors
+    SetLeds {
+        start: u8,
+        len: u8,
+        colors: [RGB8; LEDS_PER_COMMAND],
+    },
+    /// `end` is exclusive
+    StartEffect {
+        start: u8,
+        end: u8,
+        effect: Effect,
+    },
+    SetBrightness(u8),
+    SetGamma(Gamma),
+    SetGammaTable {
+        offset: u8,
+        data: [u8; GAMMA_CHUNK_SIZE],
+    },
+    /// Current limit for the whole strip in mA
+    SetPowerLimit(u16),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub enum LedError {
+    OutOfRange,
+    TooManyEffects,
+}
+
+impl LedError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            LedError::OutOfRange => ErrorCode::InvalidArgument,
+            LedError::TooManyEffects => ErrorCode::TooManyEffects,
+        }
+    }
+}
+
+/// The colors of the whole strip, and whether they changed since the last frame
+pub struct Framebuffer {
+    pixels: [RGB8; NUM_LEDS],
+    dirty: bool,
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Self {
+            pixels: [RGB8::default(); NUM_LEDS],
+            // Make sure the strip gets cleared on startup
+            dirty: true,
+        }
+    }
+
+    pub fn pixels(&self) -> &[RGB8; NUM_LEDS] {
+        &self.pixels
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Force the next frame to be sent, e.g. because the color correction changed
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn set(&mut self, idx: usize, color: RGB8) -> Result<(), LedError> {
+        self.fill_range(idx, idx + 1, color)
+    }
+
+    pub fn fill_range(&mut self, start: usize, end: usize, color: RGB8) -> Result<(), LedError> {
+        let range = self
+            .pixels
+            .get_mut(start..end)
+            .ok_or(LedError::OutOfRange)?;
+        for px in range {
+            if *px != color {
+                *px = color;
+                self.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn copy_from(&mut self, start: usize, colors: &[RGB8]) -> Result<(), LedError> {
+        let range = self
+            .pixels
+            .get_mut(start..start + colors.len())
+            .ok_or(LedError::OutOfRange)?;
+        for (px, color) in range.iter_mut().zip(colors) {
+            if px != color {
+                *px = *color;
+                self.dirty = true;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fill(&mut self, color: RGB8) {
+        // Can't be out of range
+        let _ = self.fill_range(0, NUM_LEDS, color);
+    }
+}
+
+/// State of the LED task
+struct LedState {
+    fb: Framebuffer,
+    effects: Effects,
+    correction: ColorCorrection,
+    power_limit_ma: u16,
+}
+
+impl LedState {
+    fn apply(&mut self, cmd: LedCommand, now_ms: u32) -> Result<(), LedError> {
+        match cmd {
+            LedCommand::Set { idx, color } => {
+                self.effects.cancel(idx as usize, idx as usize + 1);
+                self.fb.set(idx as usize, color)?
+            }
+            LedCommand::SetRange { start, end, color } => {
+                self.effects.cancel(start as usize, end as usize);
+                self.fb.fill_range(start as usize, end as usize, color)?
+            }
+            LedCommand::SetAll(color) => {
+                self.effects.cancel(0, NUM_LEDS);
+                self.fb.fill(color)
+            }
+            LedCommand::SetLeds { start, len, colors } => {
+                let (start, len) = (start as usize, len as usize);
+                self.effects.cancel(start, start + len);
+                self.fb.copy_from(start, &colors[..len])?
+            }
+            LedCommand::StartEffect { start, end, effect } => {
+                let current = self.fb.pixels();
+                self.effects
+                    .start(start, end, effect, now_ms, current)
+                    .map_err(|_| LedError::TooManyEffects)?
+            }
+            LedCommand::SetBrightness(brightness) => {
+                self.correction.set_brightness(brightness);
+                self.fb.mark_dirty();
+            }
+            LedCommand::SetGamma(gamma) => {
+                self.correction.set_gamma(gamma);
+                self.fb.mark_dirty();
+            }
+            LedCommand::SetGammaTable { offset, data } => {
+                self.correction.set_custom_table(offset as usize, &data);
+                self.fb.mark_dirty();
+            }
+            LedCommand::SetPowerLimit(limit_ma) => {
+                self.power_limit_ma = limit_ma;
+                self.fb.mark_dirty();
+            }
+        }
+        Ok(())
+    }
+
+    fn render_effects(&mut self, now_ms: u32) {
+        let mut pixels = *self.fb.pixels();
+        self.effects.render(now_ms, &mut pixels);
+        for (i, color) in pixels.iter().enumerate() {
+            // Can't be out of range
+            let _ = self.fb.set(i, *color);
+        }
+    }
+}
+
+/// Owns the framebuffer, applies incoming commands, renders effects and
+/// pushes changed frames to the strip every [`REFRESH_PERIOD`].
+/// Commands that fail are reported to the host through `events`
+#[embassy::task]
+pub async fn refresh_leds(mut leds: Leds, mut commands: LedReceiver, events: EventSender) {
+    let mut state = LedState {
+        fb: Framebuffer::new(),
+        effects: Effects::new(),
+        correction: ColorCorrection::new(),
+        power_limit_ma: power::DEFAULT_LIMIT_MA,
+    };
+    // Errors that could not be reported, because the event channel was full
+    let mut dropped_errors: u32 = 0;
+    let mut next_frame = Instant::now();
+    loop {
+        let now_ms = Instant::now().as_millis() as u32;
+        while let Ok((command, cmd)) = commands.try_recv() {
+            if let Err(e) = state.apply(cmd, now_ms) {
+                error!("Invalid LED command {}: {}", command, e);
+                let code = e.code();
+                // Never wait for room here: the main loop, which drains the event
+                // channel, may itself be waiting for room in `commands`
+                if events.try_send(Message::Error { command, code }).is_err() {
+                    dropped_errors = dropped_errors.wrapping_add(1);
+                    error!(
+                        "Event channel full, {} LED errors not reported",
+                        dropped_errors
+                    );
+                }
+            }
+        }
+        state.render_effects(now_ms);
+        if feedback::take_changed() {
+            state.fb.mark_dirty();
+        }
+        if state.fb.is_dirty() {
+            let mut frame = *state.fb.pixels();
+            feedback::overlay(&mut frame);
+            for c in frame.iter_mut() {
+                *c = state.correction.apply(*c);
+            }
+            power::limit(&mut frame, state.power_limit_ma);
+            leds.write(frame.iter().cloned()).await.unwrap();
+            state.fb.mark_clean();
+        }
+        next_frame += REFRESH_PERIOD;
+        Timer::at(next_frame).await;
+    }
+}
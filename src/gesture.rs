@@ -0,0 +1,269 @@
+use defmt::Format;
+
+/// Higher level key events, recognised from presses and releases
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+#[repr(u8)]
+pub enum Gesture {
+    /// Held for at least `long_press_ms`, sent while still held
+    LongPress = 0,
+    /// Pressed again within `double_tap_ms` of releasing a short press
+    DoubleTap = 1,
+    /// Sent every `repeat_interval_ms` while held, starting after `repeat_delay_ms`
+    Repeat = 2,
+}
+
+/// Gesture timing for a single key. A time of 0 disables that gesture
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Format)]
+pub struct GestureConfig {
+    pub long_press_ms: u16,
+    pub double_tap_ms: u16,
+    pub repeat_delay_ms: u16,
+    pub repeat_interval_ms: u16,
+}
+
+impl GestureConfig {
+    pub const DEFAULT: Self = Self {
+        long_press_ms: 500,
+        double_tap_ms: 300,
+        repeat_delay_ms: 0,
+        repeat_interval_ms: 0,
+    };
+
+    /// For keys like `Plus` and `Minus`, that step through values while held
+    pub const REPEATING: Self = Self {
+        long_press_ms: 0,
+        double_tap_ms: 0,
+        repeat_delay_ms: 400,
+        repeat_interval_ms: 80,
+    };
+}
+
+/// `a` is at or after `b`, for wrapping ms timestamps
+fn reached(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) >= 0
+}
+
+/// Gesture state machine for a single key.
+///
+/// Fed with debounced presses and releases, and polled regularly for the
+/// gestures that happen while a key is held. All times are in ms.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Recognizer {
+    pressed_at: Option<u32>,
+    /// Release time of the last short press, for double tap detection
+    last_tap: Option<u32>,
+    /// The current press already is a long press or the second half of a double tap,
+    /// so it shouldn't start a new tap
+    consumed: bool,
+    next_repeat: Option<u32>,
+}
+
+impl Recognizer {
+    pub fn press(&mut self, cfg: &GestureConfig, now: u32) -> Option<Gesture> {
+        self.pressed_at = Some(now);
+        self.consumed = false;
+        self.next_repeat = if cfg.repeat_delay_ms > 0 && cfg.repeat_interval_ms > 0 {
+            Some(now.wrapping_add(cfg.repeat_delay_ms as u32))
+        } else {
+            None
+        };
+        match self.last_tap.take() {
+            Some(t) if cfg.double_tap_ms > 0 && now.wrapping_sub(t) <= cfg.double_tap_ms as u32 => {
+                self.consumed = true;
+                Some(Gesture::DoubleTap)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn release(&mut self, _cfg: &GestureConfig, now: u32) {
+        if self.pressed_at.take().is_some() && !self.consumed {
+            self.last_tap = Some(now);
+        }
+        self.next_repeat = None;
+    }
+
+    /// Check for gestures that happen while the key is held
+    pub fn poll(&mut self, cfg: &GestureConfig, now: u32) -> Option<Gesture> {
+        let pressed_at = self.pressed_at?;
+        if cfg.long_press_ms > 0
+            && !self.consumed
+            && reached(now, pressed_at.wrapping_add(cfg.long_press_ms as u32))
+        {
+            self.consumed = true;
+            return Some(Gesture::LongPress);
+        }
+        match self.next_repeat {
+            Some(t) if reached(now, t) => {
+                // From `now`, so a late poll doesn't cause a burst of repeats to catch up
+                self.next_repeat = if cfg.repeat_interval_ms > 0 {
+                    Some(now.wrapping_add(cfg.repeat_interval_ms as u32))
+                } else {
+                    None
+                };
+                self.consumed = true;
+                Some(Gesture::Repeat)
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Ev {
+        Press(u32),
+        Release(u32),
+    }
+
+    /// Run a timeline of presses and releases, polling every ms until `end`,
+    /// and collect the gestures with their timestamps
+    fn run(cfg: &GestureConfig, timeline: &[Ev], end: u32) -> ([(u32, Gesture); 16], usize) {
+        let mut r = Recognizer::default();
+        let mut out = [(0, Gesture::LongPress); 16];
+        let mut n = 0;
+        let mut events = timeline.iter().peekable();
+        for now in 0..=end {
+            while let Some(ev) = events.peek() {
+                let g = match **ev {
+                    Ev::Press(t) if t == now => r.press(cfg, now),
+                    Ev::Release(t) if t == now => {
+                        r.release(cfg, now);
+                        None
+                    }
+                    _ => break,
+                };
+                if let Some(g) = g {
+                    out[n] = (now, g);
+                    n += 1;
+                }
+                events.next();
+            }
+            if let Some(g) = r.poll(cfg, now) {
+                out[n] = (now, g);
+                n += 1;
+            }
+        }
+        (out, n)
+    }
+
+    #[test]
+    fn short_press_is_no_gesture() {
+        let (_, n) = run(
+            &GestureConfig::DEFAULT,
+            &[Ev::Press(10), Ev::Release(100)],
+            2000,
+        );
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn long_press_fires_once_while_held() {
+        let (out, n) = run(
+            &GestureConfig::DEFAULT,
+            &[Ev::Press(10), Ev::Release(2000)],
+            3000,
+        );
+        assert_eq!(&out[..n], &[(510, Gesture::LongPress)]);
+    }
+
+    #[test]
+    fn double_tap() {
+        let timeline = [
+            Ev::Press(0),
+            Ev::Release(50),
+            Ev::Press(300),
+            Ev::Release(350),
+        ];
+        let (out, n) = run(&GestureConfig::DEFAULT, &timeline, 1000);
+        assert_eq!(&out[..n], &[(300, Gesture::DoubleTap)]);
+    }
+
+    #[test]
+    fn slow_second_tap_is_no_double_tap() {
+        let timeline = [
+            Ev::Press(0),
+            Ev::Release(50),
+            Ev::Press(400),
+            Ev::Release(450),
+        ];
+        let (_, n) = run(&GestureConfig::DEFAULT, &timeline, 1000);
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn long_press_does_not_start_double_tap() {
+        let timeline = [
+            Ev::Press(0),
+            Ev::Release(600),
+            Ev::Press(700),
+            Ev::Release(750),
+        ];
+        let (out, n) = run(&GestureConfig::DEFAULT, &timeline, 1000);
+        assert_eq!(&out[..n], &[(500, Gesture::LongPress)]);
+    }
+
+    #[test]
+    fn triple_tap_is_one_double_tap() {
+        let timeline = [
+            Ev::Press(0),
+            Ev::Release(50),
+            Ev::Press(100),
+            Ev::Release(150),
+            Ev::Press(200),
+            Ev::Release(250),
+        ];
+        let (out, n) = run(&GestureConfig::DEFAULT, &timeline, 1000);
+        assert_eq!(&out[..n], &[(100, Gesture::DoubleTap)]);
+    }
+
+    #[test]
+    fn repeat_while_held() {
+        let (out, n) = run(
+            &GestureConfig::REPEATING,
+            &[Ev::Press(0), Ev::Release(700)],
+            1000,
+        );
+        let times: [u32; 4] = [400, 480, 560, 640];
+        assert_eq!(n, times.len());
+        for (i, t) in times.iter().enumerate() {
+            assert_eq!(out[i], (*t, Gesture::Repeat));
+        }
+    }
+
+    #[test]
+    fn zero_interval_disables_repeat() {
+        let cfg = GestureConfig {
+            repeat_interval_ms: 0,
+            ..GestureConfig::REPEATING
+        };
+        let (_, n) = run(&cfg, &[Ev::Press(0), Ev::Release(700)], 1000);
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn late_poll_repeats_once() {
+        let mut r = Recognizer::default();
+        let cfg = GestureConfig::REPEATING;
+        assert_eq!(r.press(&cfg, 0), None);
+        assert_eq!(r.poll(&cfg, 1000), Some(Gesture::Repeat));
+        assert_eq!(r.poll(&cfg, 1001), None);
+        assert_eq!(r.poll(&cfg, 1079), None);
+        assert_eq!(r.poll(&cfg, 1080), Some(Gesture::Repeat));
+    }
+
+    #[test]
+    fn timestamps_wrap() {
+        let mut r = Recognizer::default();
+        let cfg = GestureConfig::DEFAULT;
+        let start = u32::MAX - 100;
+        assert_eq!(r.press(&cfg, start), None);
+        assert_eq!(r.poll(&cfg, start.wrapping_add(499)), None);
+        assert_eq!(
+            r.poll(&cfg, start.wrapping_add(500)),
+            Some(Gesture::LongPress)
+        );
+    }
+}
@@ -0,0 +1,201 @@
+use num_enum::TryFromPrimitive;
+
+/// How to deal with keys that could be ghosts, see [`ambiguous`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive, defmt::Format)]
+#[repr(u8)]
+pub enum Ghosting {
+    /// The board has a diode per key, so there are no ghosts
+    Diodes = 0,
+    /// Don't report presses of ambiguous keys until they are no longer ambiguous.
+    /// Also holds back the real key that completes a rectangle
+    Suppress = 1,
+    /// Report ambiguous presses, but mark them as uncertain
+    Flag = 2,
+}
+
+impl Ghosting {
+    /// Until it's known whether the board has diodes
+    pub const DEFAULT: Self = Ghosting::Flag;
+}
+
+/// Find keys that might be ghosts in a matrix without diodes.
+///
+/// `columns[c]` has bit `r` set if row `r` reads as pressed while driving column `c`.
+/// When three corners of a rectangle are pressed, current flows backwards through
+/// one of them and the fourth corner reads as pressed too. From the readings alone
+/// it's impossible to tell which corner is the ghost, so all four are ambiguous.
+/// Unless one corner has no switch, as listed in `populated`: that corner is the ghost,
+/// and the other three are real.
+///
+/// Returns the ambiguous keys in the same layout.
+pub fn ambiguous<const COLS: usize>(columns: &[u8; COLS], populated: &[u8; COLS]) -> [u8; COLS] {
+    let mut res = [0; COLS];
+    for c1 in 0..COLS {
+        for c2 in c1 + 1..COLS {
+            // Rows that read as pressed in both columns, and have a switch in both
+            let common = columns[c1] & columns[c2] & populated[c1] & populated[c2];
+            if common.count_ones() >= 2 {
+                res[c1] |= common;
+                res[c2] |= common;
+            }
+        }
+    }
+    res
+}
+
+/// Update the reported key states from a new debounced scan, in the layout of [`ambiguous`].
+///
+/// Only keys whose debounced state differs from `reported` are touched. Their
+/// `uncertain` bit is set if they were reported as pressed while ambiguous.
+/// Returns true if any reported state changed
+pub fn resolve<const COLS: usize>(
+    mode: Ghosting,
+    populated: &[u8; COLS],
+    debounced: &[u8; COLS],
+    reported: &mut [u8; COLS],
+    uncertain: &mut [u8; COLS],
+) -> bool {
+    let ambiguous = match mode {
+        Ghosting::Diodes => [0; COLS],
+        Ghosting::Suppress | Ghosting::Flag => ambiguous(debounced, populated),
+    };
+    let mut has_changed = false;
+    for c in 0..COLS {
+        let mut changed = debounced[c] ^ reported[c];
+        if mode == Ghosting::Suppress {
+            // Hold back new presses of ambiguous keys, releases always go through
+            changed &= !(debounced[c] & ambiguous[c]);
+        }
+        reported[c] = (reported[c] & !changed) | (debounced[c] & changed);
+        uncertain[c] = (uncertain[c] & !changed) | (debounced[c] & ambiguous[c] & changed);
+        has_changed |= changed != 0;
+    }
+    has_changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A switch at every position
+    const ALL: [u8; 8] = [0xFF; 8];
+
+    /// Build a matrix from `(row, col)` pairs
+    fn matrix(keys: &[(usize, usize)]) -> [u8; 8] {
+        let mut m = [0; 8];
+        for &(r, c) in keys {
+            m[c] |= 1 << r;
+        }
+        m
+    }
+
+    #[test]
+    fn no_ghosts_without_rectangle() {
+        let m = matrix(&[(0, 0), (0, 1), (1, 2), (5, 5), (5, 7)]);
+        assert_eq!(ambiguous(&m, &ALL), [0; 8]);
+    }
+
+    #[test]
+    fn full_rectangle_is_ambiguous() {
+        // (2, 4) pressed, plus the phantom (6, 1)
+        let m = matrix(&[(2, 1), (2, 4), (6, 4), (6, 1)]);
+        assert_eq!(ambiguous(&m, &ALL), m);
+    }
+
+    #[test]
+    fn only_rectangle_corners_are_flagged() {
+        let m = matrix(&[(0, 0), (0, 3), (7, 0), (7, 3), (4, 5), (1, 1)]);
+        assert_eq!(
+            ambiguous(&m, &ALL),
+            matrix(&[(0, 0), (0, 3), (7, 0), (7, 3)])
+        );
+    }
+
+    #[test]
+    fn shared_row_and_column_without_fourth_corner() {
+        // An L shape can't produce a ghost on its own
+        let m = matrix(&[(3, 3), (3, 6), (5, 3)]);
+        assert_eq!(ambiguous(&m, &ALL), [0; 8]);
+    }
+
+    #[test]
+    fn overlapping_rectangles() {
+        let m = matrix(&[(0, 0), (0, 1), (1, 0), (1, 1), (1, 2), (0, 2)]);
+        assert_eq!(ambiguous(&m, &ALL), m);
+    }
+
+    /// Press (0, 0) and (0, 1), then (1, 0), which also makes the ghost (1, 1) appear.
+    /// Returns the reported and uncertain states after each step
+    fn press_l_shape(mode: Ghosting) -> [([u8; 8], [u8; 8]); 2] {
+        let (mut reported, mut uncertain) = ([0; 8], [0; 8]);
+        let two = matrix(&[(0, 0), (0, 1)]);
+        assert!(resolve(mode, &ALL, &two, &mut reported, &mut uncertain));
+        assert_eq!(reported, two);
+        let first = (reported, uncertain);
+        let with_ghost = matrix(&[(0, 0), (0, 1), (1, 0), (1, 1)]);
+        resolve(mode, &ALL, &with_ghost, &mut reported, &mut uncertain);
+        [first, (reported, uncertain)]
+    }
+
+    #[test]
+    fn diodes_report_everything() {
+        let [_, (reported, uncertain)] = press_l_shape(Ghosting::Diodes);
+        assert_eq!(reported, matrix(&[(0, 0), (0, 1), (1, 0), (1, 1)]));
+        assert_eq!(uncertain, [0; 8]);
+    }
+
+    #[test]
+    fn flag_marks_new_ambiguous_presses() {
+        let [_, (reported, uncertain)] = press_l_shape(Ghosting::Flag);
+        assert_eq!(reported, matrix(&[(0, 0), (0, 1), (1, 0), (1, 1)]));
+        // Keys reported before the rectangle formed stay certain
+        assert_eq!(uncertain, matrix(&[(1, 0), (1, 1)]));
+    }
+
+    #[test]
+    fn suppress_holds_back_until_unambiguous() {
+        let [_, (mut reported, mut uncertain)] = press_l_shape(Ghosting::Suppress);
+        assert_eq!(reported, matrix(&[(0, 0), (0, 1)]));
+        assert_eq!(uncertain, [0; 8]);
+
+        // Releasing (0, 1) removes the ghost, so (1, 0) goes through
+        let released = matrix(&[(0, 0), (1, 0)]);
+        assert!(resolve(
+            Ghosting::Suppress,
+            &ALL,
+            &released,
+            &mut reported,
+            &mut uncertain
+        ));
+        assert_eq!(reported, released);
+        assert_eq!(uncertain, [0; 8]);
+    }
+
+    #[test]
+    fn corner_without_switch_settles_rectangle() {
+        let mut populated = ALL;
+        populated[1] &= !(1 << 1);
+        let m = matrix(&[(0, 0), (0, 1), (1, 0), (1, 1)]);
+        assert_eq!(ambiguous(&m, &populated), [0; 8]);
+
+        for mode in [Ghosting::Flag, Ghosting::Suppress] {
+            let (mut reported, mut uncertain) = ([0; 8], [0; 8]);
+            assert!(resolve(mode, &populated, &m, &mut reported, &mut uncertain));
+            assert_eq!(reported, m);
+            assert_eq!(uncertain, [0; 8]);
+        }
+    }
+
+    #[test]
+    fn unchanged_scan_reports_nothing() {
+        let m = matrix(&[(3, 3)]);
+        let (mut reported, mut uncertain) = (m, [0; 8]);
+        assert!(!resolve(
+            Ghosting::Flag,
+            &ALL,
+            &m,
+            &mut reported,
+            &mut uncertain
+        ));
+    }
+}
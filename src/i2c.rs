@@ -8,6 +8,7 @@ use cortex_m::peripheral::{scb::VectActive, SCB};
 use defmt::{error, trace};
 use embassy::{
     interrupt::{Interrupt, InterruptExt},
+    time::{Duration, Instant},
     util::Unborrow,
     waitqueue::WakerRegistration,
 };
@@ -20,7 +21,10 @@ use embassy_stm32::{
 };
 use futures::Future;
 
-use crate::cmd::PacketData;
+use crate::cmd::{Frame, Message, MessageFrame};
+use crate::crc;
+use crate::events::{EventQueue, Popped};
+use crate::registers;
 
 pub trait InstanceExt: Instance {
     type ErInterrupt: Interrupt;
@@ -59,6 +63,150 @@ impl<'d, T: Pin> AfPin<'d, T> {
     }
 }
 
+/// Open-drain output telling the host that packets are waiting in the TX queue.
+/// Active low, so the host can share the line or use its internal pull-up
+struct DataReadyPin<'d, T: Pin> {
+    pin: T,
+    phantom: PhantomData<&'d mut T>,
+}
+
+impl<'d, T: Pin> DataReadyPin<'d, T> {
+    fn new(pin: T) -> Self {
+        cortex_m::interrupt::free(|_| {
+            let r = pin.block();
+            let n = pin._pin() as usize;
+            let crlh = if n < 8 { 0 } else { 1 };
+            unsafe {
+                // Start released
+                r.bsrr().write(|w| w.set_bs(n, true));
+                r.cr(crlh).modify(|w| {
+                    w.set_mode(n % 8, gpio::vals::Mode::OUTPUT50);
+                    w.set_cnf(n % 8, gpio::vals::Cnf::OPENDRAIN);
+                });
+            }
+        });
+        Self {
+            pin,
+            phantom: PhantomData::default(),
+        }
+    }
+
+    fn set_asserted(&self, asserted: bool) {
+        let r = self.pin.block();
+        let n = self.pin._pin() as usize;
+        unsafe {
+            if asserted {
+                r.bsrr().write(|w| w.set_br(n, true));
+            } else {
+                r.bsrr().write(|w| w.set_bs(n, true));
+            }
+        }
+    }
+}
+
+/// A frame that was received but dropped. Holds its type byte
+#[derive(Debug, Clone, Copy, Eq, PartialEq, defmt::Format)]
+pub enum FrameError {
+    /// Didn't fit into the receive buffer
+    TooLong(u8),
+    /// Failed the PEC check
+    BadPec(u8),
+}
+
+pub type Received = Result<Frame, FrameError>;
+
+/// Link health counters, all wrapping
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, defmt::Format)]
+pub struct Diagnostics {
+    /// Packet mode writes, including rejected ones
+    pub frames_received: u32,
+    /// Frames read by the host, including [`Message::EventsLost`]
+    pub frames_sent: u32,
+    /// Reads while nothing was queued, answered with [`Message::None`]
+    pub empty_reads: u32,
+    pub berr: u32,
+    pub arlo: u32,
+    /// Acknowledge failures, except for the NACK that normally ends a read
+    pub af: u32,
+    pub ovr: u32,
+    pub pecerr: u32,
+    pub timeout: u32,
+    /// Frames rejected with [`FrameError::TooLong`]
+    pub oversize_frames: u32,
+    /// Frames rejected with [`FrameError::BadPec`]
+    pub bad_pecs: u32,
+    /// Events dropped because the TX queue was full
+    pub queue_overflows: u32,
+    /// Times the peripheral was reset by [`I2cSlave::recover_if_stuck`]
+    pub resets: u32,
+}
+
+impl Diagnostics {
+    pub const SIZE: usize = 13 * 4;
+
+    /// All counters as little endian u32, in declaration order
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let counters = [
+            self.frames_received,
+            self.frames_sent,
+            self.empty_reads,
+            self.berr,
+            self.arlo,
+            self.af,
+            self.ovr,
+            self.pecerr,
+            self.timeout,
+            self.oversize_frames,
+            self.bad_pecs,
+            self.queue_overflows,
+            self.resets,
+        ];
+        let mut res = [0; Self::SIZE];
+        for (i, c) in counters.iter().enumerate() {
+            res[4 * i..4 * i + 4].copy_from_slice(&c.to_le_bytes());
+        }
+        res
+    }
+}
+
+fn inc(counter: &mut u32) {
+    *counter = counter.wrapping_add(1);
+}
+
+/// Set up the peripheral as a slave on `add`, and on `register_add` for register mode.
+/// Done on creation, and again after a reset
+fn configure<T: Instance>(add: u8, register_add: u8) {
+    unsafe {
+        T::regs().cr1().modify(|reg| {
+            reg.set_pe(false);
+        });
+        T::regs().cr1().modify(|reg| {
+            reg.set_engc(false);
+            reg.set_nostretch(true);
+        });
+        T::regs().oar1().modify(|reg| {
+            reg.set_addmode(vals::Addmode::ADD7);
+            reg.set_add((add as u16) << 1);
+        });
+        T::regs().oar2().modify(|reg| {
+            reg.set_endual(vals::Endual::DUAL);
+            reg.set_add2(register_add);
+        });
+        T::regs().cr1().modify(|reg| {
+            reg.set_pe(true);
+        });
+        T::regs().cr1().modify(|reg| {
+            reg.set_pos(vals::Pos::CURRENT);
+            reg.set_ack(true);
+        });
+        T::regs().cr2().modify(|reg| {
+            reg.set_itbufen(true);
+            reg.set_iterren(true);
+            reg.set_itevten(true);
+        });
+    }
+}
+
 pub struct State<'d, T: InstanceExt>(MaybeUninit<StateInner<'d, T>>);
 impl<'d, T: InstanceExt> State<'d, T> {
     pub fn new() -> Self {
@@ -80,73 +228,73 @@ impl<'d, T: InstanceExt> I2cSlave<'d, T> {
         p: impl Unborrow<Target = T> + 'd,
         scl: impl Unborrow<Target = impl SclPin<T>> + 'd,
         sda: impl Unborrow<Target = impl SdaPin<T>> + 'd,
+        data_ready: impl Unborrow<Target = impl Pin> + 'd,
         ev_irq: T::Interrupt,
         er_irq: T::ErInterrupt,
         add: u16,
+        register_add: u16,
     ) -> Self
     where
         'd: 'static,
     {
-        unsafe { Self::new_unchecked(state, p, scl, sda, ev_irq, er_irq, add) }
+        unsafe {
+            Self::new_unchecked(
+                state,
+                p,
+                scl,
+                sda,
+                data_ready,
+                ev_irq,
+                er_irq,
+                add,
+                register_add,
+            )
+        }
     }
 
+    /// Packet mode frames are exchanged on `add`. On `register_add`, the slave behaves like
+    /// a register based peripheral instead, see [`registers::read`].
+    ///
     /// Safety: The instance must not be leaked (drop must be run), since otherwise, the interrupts will not be disabled.
     pub unsafe fn new_unchecked(
         state: &'d mut State<'d, T>,
         p: impl Unborrow<Target = T> + 'd,
         scl: impl Unborrow<Target = impl SclPin<T>> + 'd,
         sda: impl Unborrow<Target = impl SdaPin<T>> + 'd,
+        data_ready: impl Unborrow<Target = impl Pin> + 'd,
         ev_irq: T::Interrupt,
         er_irq: T::ErInterrupt,
         add: u16,
+        register_add: u16,
     ) -> Self {
-        unborrow!(scl, sda);
+        unborrow!(scl, sda, data_ready);
 
         let scl = scl.degrade();
         let sda = sda.degrade();
+        let data_ready = DataReadyPin::new(data_ready.degrade());
 
         T::enable();
 
         let scl = AfPin::new(scl);
         let sda = AfPin::new(sda);
 
-        unsafe {
-            T::regs().cr1().modify(|reg| {
-                reg.set_pe(false);
-            });
-            T::regs().cr1().modify(|reg| {
-                reg.set_engc(false);
-                reg.set_nostretch(true);
-            });
-            T::regs().oar1().modify(|reg| {
-                reg.set_addmode(vals::Addmode::ADD7);
-                reg.set_add(add << 1);
-            });
-            T::regs().oar2().modify(|reg| {
-                reg.set_endual(vals::Endual::SINGLE);
-            });
-            T::regs().cr1().modify(|reg| {
-                reg.set_pe(true);
-            });
-            T::regs().cr1().modify(|reg| {
-                reg.set_pos(vals::Pos::CURRENT);
-                reg.set_ack(true);
-            });
-            T::regs().cr2().modify(|reg| {
-                reg.set_itbufen(true);
-                reg.set_iterren(true);
-                reg.set_itevten(true);
-            });
-        }
+        configure::<T>(add as u8, register_add as u8);
 
         let state_ptr = state.0.as_mut_ptr();
 
         *state_ptr = StateInner {
             scl,
             sda,
+            data_ready,
             phantom: PhantomData::default(),
+            address: add as u8,
+            register_address: register_add as u8,
+            register: 0,
+            registers: registers::snapshot(),
             stage: Stage::Waiting,
-            tx_buffer: Default::default(),
+            tx_buffer: EventQueue::new(),
+            diagnostics: Diagnostics::default(),
+            last_activity: Instant::now(),
             rx_waker: WakerRegistration::new(),
         };
 
@@ -190,9 +338,39 @@ impl<'d, T: InstanceExt> I2cSlave<'d, T> {
         r
     }
 
-    /// Returns the packet as Err if the queue is full
-    pub fn enqueue(&mut self, packet: PacketData) -> Result<(), PacketData> {
-        self.with_inner(|s| s.tx_buffer.enqueue(packet))
+    /// Returns the frame as Err if the queue is full.
+    /// The host is told about dropped frames with a [`Message::EventsLost`]
+    pub fn enqueue(&mut self, frame: MessageFrame) -> Result<(), MessageFrame> {
+        self.with_inner(|s| {
+            let res = s.tx_buffer.push(frame);
+            s.update_data_ready();
+            res
+        })
+    }
+
+    /// Reset the peripheral if it's stuck. Call at least every [`STUCK_TIMEOUT`].
+    /// Returns true if it was reset
+    pub fn recover_if_stuck(&mut self) -> bool {
+        self.with_inner(|s| s.recover_if_stuck())
+    }
+
+    /// Refresh the key and encoder registers read in register mode. Call whenever they
+    /// may have changed
+    pub fn update_registers(&mut self) {
+        let map = registers::snapshot();
+        self.with_inner(|s| s.registers = map);
+    }
+
+    pub fn diagnostics(&mut self) -> Diagnostics {
+        self.with_inner(|s| s.diagnostics())
+    }
+
+    /// Reset all [`Diagnostics`] counters to zero
+    pub fn clear_diagnostics(&mut self) {
+        self.with_inner(|s| {
+            s.diagnostics = Diagnostics::default();
+            s.tx_buffer.clear_overflows();
+        })
     }
 
     pub fn receive_message(&mut self) -> Read<'_, Self> {
@@ -202,8 +380,8 @@ impl<'d, T: InstanceExt> I2cSlave<'d, T> {
     pub fn poll_received(
         mut self: core::pin::Pin<&mut Self>,
         cx: &mut Context<'_>,
-    ) -> Poll<PacketData> {
-        self.with_inner(|state| state.poll_received_packet(cx))
+    ) -> Poll<Received> {
+        self.with_inner(|state| state.poll_received_frame(cx))
     }
 }
 
@@ -213,64 +391,163 @@ pub struct Read<'a, R> {
 }
 
 impl<'d, T: InstanceExt> Future for Read<'_, I2cSlave<'d, T>> {
-    type Output = PacketData;
+    type Output = Received;
 
     fn poll(mut self: core::pin::Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         core::pin::Pin::new(&mut *self.i2cslave).poll_received(cx)
     }
 }
 
+/// Bytes prepared for a read, a frame or part of the register map
+type TxData = heapless::Vec<u8, TX_DATA_SIZE>;
+
+/// Longest read prepared in one go. Has to hold a [`MessageFrame`]
+const TX_DATA_SIZE: usize = 64;
+
 #[derive(Clone)]
 pub enum Stage {
     Waiting,
-    Transmitting(PacketData, usize),
-    Receiving(heapless::Vec<u8, 64>),
-    ReceivedDataReady(PacketData),
+    Transmitting(TxData, usize),
+    /// `too_long` is set once the frame overflowed the buffer, the rest is discarded
+    Receiving {
+        buf: Frame,
+        too_long: bool,
+    },
+    ReceivedDataReady(Received),
+    /// Register mode write, the first byte sets the register address
+    RegisterWrite {
+        got_address: bool,
+    },
 }
 
-// Size of TX buffer in number of packets
+/// The peripheral is considered stuck if the bus is busy without any interrupts for this long
+pub const STUCK_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Size of TX buffer in number of frames, holds one less
 const TX_BUFFER_SIZE: usize = 16;
 
 pub struct StateInner<'d, T: InstanceExt> {
     scl: AfPin<'d, AnyPin>,
     sda: AfPin<'d, AnyPin>,
+    data_ready: DataReadyPin<'d, AnyPin>,
     phantom: PhantomData<&'d mut T>,
 
+    /// 7-bit slave address, part of the PEC
+    address: u8,
+    /// 7-bit slave address for register mode
+    register_address: u8,
+    /// Register address for register mode reads
+    register: u8,
+    /// Register map, refreshed by [`I2cSlave::update_registers`]
+    registers: registers::Map,
     stage: Stage,
-    tx_buffer: heapless::spsc::Queue<PacketData, TX_BUFFER_SIZE>,
+    tx_buffer: EventQueue<MessageFrame, TX_BUFFER_SIZE>,
+    /// Queue overflows are counted by `tx_buffer`, see [`StateInner::diagnostics`]
+    diagnostics: Diagnostics,
+    /// Last interrupt, to detect a stuck peripheral
+    last_activity: Instant,
     rx_waker: WakerRegistration,
 }
 
 impl<'d, T: InstanceExt> StateInner<'d, T> {
+    /// Assert the data ready line while there is anything left for the host to read
+    fn update_data_ready(&self) {
+        self.data_ready.set_asserted(!self.tx_buffer.is_empty());
+    }
+
+    fn diagnostics(&self) -> Diagnostics {
+        Diagnostics {
+            queue_overflows: self.tx_buffer.overflows(),
+            ..self.diagnostics
+        }
+    }
+
+    /// Pop the next frame for the host, or report that nothing is queued
+    fn pop_frame(&mut self) -> MessageFrame {
+        let frame = match self.tx_buffer.pop() {
+            Some(Popped::Event(frame)) => frame,
+            Some(Popped::Lost(count)) => Message::EventsLost { count }.encode(),
+            None => {
+                inc(&mut self.diagnostics.empty_reads);
+                return Message::None.encode();
+            }
+        };
+        inc(&mut self.diagnostics.frames_sent);
+        self.update_data_ready();
+        frame
+    }
+
+    fn read_registers(&mut self) -> TxData {
+        let mut data = TxData::new();
+        if self.register == registers::EVENT_REG {
+            // Always fits
+            let _ = data.extend_from_slice(&self.pop_frame());
+        } else {
+            let events_pending = !self.tx_buffer.is_empty();
+            let diagnostics = self.diagnostics();
+            let bytes = registers::read(
+                &mut self.registers,
+                self.register,
+                events_pending,
+                &diagnostics,
+            );
+            // Reading past the end of the data returns zeros, like unused registers
+            let _ = data.extend_from_slice(&bytes[..bytes.len().min(TX_DATA_SIZE)]);
+        }
+        data
+    }
+
     fn on_event(&mut self) {
         let regs = T::regs();
         let sr1 = unsafe { regs.sr1().read() };
+        self.last_activity = Instant::now();
 
         // trace!("ev");
         if sr1.addr() {
             // trace!("addr");
             // clear addr by reading sr2 after reading sr1
             let sr2 = unsafe { regs.sr2().read() };
-            if sr2.tra() {
-                self.stage = Stage::Transmitting(Default::default(), 0);
+            if sr2.dualf() {
+                // Register mode
+                self.stage = if sr2.tra() {
+                    Stage::Transmitting(self.read_registers(), 0)
+                } else {
+                    Stage::RegisterWrite { got_address: false }
+                };
+            } else if sr2.tra() {
+                // Every read pops one frame. Reading past the end of the frame returns zeros
+                let mut frame = self.pop_frame();
+                // There is always room for the PEC
+                let pec = crc::pec(self.address, true, &frame);
+                let _ = frame.push(pec);
+                // Always fits
+                let data = TxData::from_slice(&frame).unwrap_or_default();
+                self.stage = Stage::Transmitting(data, 0);
             } else {
-                self.stage = Stage::Receiving(heapless::Vec::new());
+                self.stage = Stage::Receiving {
+                    buf: Frame::new(),
+                    too_long: false,
+                };
             }
-        } else if let Stage::Transmitting(tx, idx) = self.stage {
+        } else if let Stage::Transmitting(tx, idx) = &mut self.stage {
             // trace!("tx");
             if sr1.tx_e() {
                 // Transmit next byte
-                unsafe { regs.dr().write(|dr| dr.set_dr(*tx.get(idx).unwrap_or(&0))) }
-                self.stage = Stage::Transmitting(tx, idx + 1);
+                unsafe { regs.dr().write(|dr| dr.set_dr(*tx.get(*idx).unwrap_or(&0))) }
+                *idx += 1;
             }
-        } else if let Stage::Receiving(rx_buf) = &mut self.stage {
+        } else if let Stage::Receiving { buf, too_long } = &mut self.stage {
             // trace!("rx");
             if sr1.rx_ne() {
                 let byte = unsafe { regs.dr().read().dr() };
-                if rx_buf.push(byte).is_err() {
-                    error!("Error receiving message - message too long!");
-                    // TODO: Reset
+                if buf.push(byte).is_err() && !*too_long {
+                    error!("Frame too long, discarding");
+                    *too_long = true;
                 }
+            } else if sr1.stopf() && buf.is_empty() {
+                // A bus scan or SMBus quick command, not a frame
+                unsafe { regs.cr1().modify(|_| {}) }
+                self.stage = Stage::Waiting;
             } else if sr1.stopf() {
                 unsafe {
                     // Clear stopf by writing to cr1
@@ -278,15 +555,35 @@ impl<'d, T: InstanceExt> StateInner<'d, T> {
                     // Disable ack until data has been read from buffer
                     regs.cr1().modify(|x| x.set_ack(false));
                 }
-                let packet = core::mem::take(rx_buf)
-                    .into_array::<17>()
-                    .unwrap_or_else(|e| {
-                        error!("Received packet of length {}, expected 17", e.len());
-                        Default::default()
-                    });
-                self.stage = Stage::ReceivedDataReady(packet);
+                let mut frame = core::mem::take(buf);
+                let command = frame.first().copied().unwrap_or(0);
+                let pec = frame.pop();
+                inc(&mut self.diagnostics.frames_received);
+                let received = if *too_long {
+                    inc(&mut self.diagnostics.oversize_frames);
+                    Err(FrameError::TooLong(command))
+                } else if pec.map_or(false, |pec| pec != crc::pec(self.address, false, &frame)) {
+                    inc(&mut self.diagnostics.bad_pecs);
+                    Err(FrameError::BadPec(command))
+                } else {
+                    Ok(frame)
+                };
+                self.stage = Stage::ReceivedDataReady(received);
                 self.rx_waker.wake();
             }
+        } else if let Stage::RegisterWrite { got_address } = &mut self.stage {
+            if sr1.rx_ne() {
+                let byte = unsafe { regs.dr().read().dr() };
+                // All registers are read-only, ignore anything after the address
+                if !*got_address {
+                    self.register = byte;
+                    *got_address = true;
+                }
+            } else if sr1.stopf() {
+                // Clear stopf by writing to cr1
+                unsafe { regs.cr1().modify(|_| {}) }
+                self.stage = Stage::Waiting;
+            }
         } else {
             trace!("Unknown error");
             if sr1.stopf() {
@@ -309,11 +606,12 @@ impl<'d, T: InstanceExt> StateInner<'d, T> {
     fn on_error(&mut self) {
         let regs = T::regs();
         let sr1 = unsafe { regs.sr1().read() };
+        self.last_activity = Instant::now();
         if let (Stage::Transmitting(..), true) = (&self.stage, sr1.af()) {
             // RM0008 fig 241: EV3-2
             // Was transmitting, got nack / stop condition.
-            // If the whole packet was transmitted, pop it off
-            error!("Was transmitting, got nack/stop");
+            // This is how the master ends a read, the packet was already popped off on ADDR
+            trace!("Was transmitting, got nack/stop");
             self.stage = Stage::Waiting;
             unsafe { regs.sr1().modify(|x| x.set_af(false)) };
         } else {
@@ -327,6 +625,19 @@ impl<'d, T: InstanceExt> StateInner<'d, T> {
                 if sr1.timeout() { "TIMEOUT " } else { "" },
                 if sr1.smbalert() { "SMBALERT " } else { "" },
             );
+            let d = &mut self.diagnostics;
+            for (flag, counter) in [
+                (sr1.berr(), &mut d.berr),
+                (sr1.arlo(), &mut d.arlo),
+                (sr1.af(), &mut d.af),
+                (sr1.ovr(), &mut d.ovr),
+                (sr1.pecerr(), &mut d.pecerr),
+                (sr1.timeout(), &mut d.timeout),
+            ] {
+                if flag {
+                    inc(counter);
+                }
+            }
             unsafe {
                 regs.cr1().modify(|x| x.set_ack(true));
                 regs.sr1().write_value(pac::i2c::regs::Sr1(0));
@@ -352,14 +663,41 @@ impl<'d, T: InstanceExt> StateInner<'d, T> {
         }
     }
 
-    fn poll_received_packet(&mut self, cx: &mut Context<'_>) -> Poll<PacketData> {
-        if let Stage::ReceivedDataReady(x) = self.stage {
-            self.stage = Stage::Waiting;
-            unsafe { T::regs().cr1().modify(|x| x.set_ack(true)) }
-            Poll::Ready(x)
+    /// The F1 I2C peripheral can lock up with BUSY set after glitches on the bus (see the
+    /// errata sheet). Resets it if that's the case, returns true if it did
+    fn recover_if_stuck(&mut self) -> bool {
+        let regs = T::regs();
+        let busy = unsafe { regs.sr2().read().busy() };
+        if !busy || Instant::now().duration_since(self.last_activity) < STUCK_TIMEOUT {
+            return false;
+        }
+        unsafe {
+            regs.cr1().modify(|x| x.set_swrst(true));
+            regs.cr1().modify(|x| x.set_swrst(false));
+        }
+        configure::<T>(self.address, self.register_address);
+        if let Stage::ReceivedDataReady(_) = self.stage {
+            // Keep the frame, and keep NACKing until it has been taken
+            unsafe { regs.cr1().modify(|x| x.set_ack(false)) }
         } else {
-            self.rx_waker.register(cx.waker());
-            Poll::Pending
+            self.stage = Stage::Waiting;
+        }
+        self.last_activity = Instant::now();
+        inc(&mut self.diagnostics.resets);
+        true
+    }
+
+    fn poll_received_frame(&mut self, cx: &mut Context<'_>) -> Poll<Received> {
+        match core::mem::replace(&mut self.stage, Stage::Waiting) {
+            Stage::ReceivedDataReady(x) => {
+                unsafe { T::regs().cr1().modify(|x| x.set_ack(true)) }
+                Poll::Ready(x)
+            }
+            stage => {
+                self.stage = stage;
+                self.rx_waker.register(cx.waker());
+                Poll::Pending
+            }
         }
     }
 }
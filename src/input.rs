@@ -1,12 +1,136 @@
-use defmt::info;
-use embassy::time::{Duration, Timer};
+use core::cell::RefCell;
+
+use cortex_m::interrupt::Mutex;
+use defmt::{error, info};
+use embassy::channel::mpsc::{Sender, WithNoThreads};
+use embassy::time::{Duration, Instant, Timer};
 use embassy_stm32::gpio::{AnyPin, Pin};
 use embassy_stm32::Peripherals;
 use num_enum::TryFromPrimitive;
 
-use crate::keys::KeyMatrix;
+use crate::cmd::{self, Message};
+use crate::debounce;
+use crate::feedback;
+use crate::gesture::{GestureConfig, Recognizer};
+use crate::ghosting::Ghosting;
+use crate::keys::{self, KeyMatrix};
+use crate::util::bitarray::BitArray;
+
+/// Number of events that can be waiting to be handed to the I2C TX queue
+pub const EVENT_CHANNEL_SIZE: usize = 8;
+
+pub type EventSender = Sender<'static, WithNoThreads, Message, EVENT_CHANNEL_SIZE>;
+
+/// Number of [`Key`] values, including `Key::None`
+pub const NUM_KEYS: usize = 59;
+
+const fn default_gesture_configs() -> [GestureConfig; NUM_KEYS] {
+    let mut res = [GestureConfig::DEFAULT; NUM_KEYS];
+    res[Key::Plus as usize] = GestureConfig::REPEATING;
+    res[Key::Minus as usize] = GestureConfig::REPEATING;
+    res
+}
+
+/// Gesture timing per key, indexed by `Key as u8`
+static GESTURE_CONFIGS: Mutex<RefCell<[GestureConfig; NUM_KEYS]>> =
+    Mutex::new(RefCell::new(default_gesture_configs()));
+
+pub fn set_gesture_config(key: Key, config: GestureConfig) {
+    cortex_m::interrupt::free(|cs| GESTURE_CONFIGS.borrow(cs).borrow_mut()[key as usize] = config);
+}
+
+fn gesture_config(key: Key) -> GestureConfig {
+    cortex_m::interrupt::free(|cs| GESTURE_CONFIGS.borrow(cs).borrow()[key as usize])
+}
+
+/// How the matrix deals with ghosts, applied before each scan
+static GHOSTING: Mutex<RefCell<Ghosting>> = Mutex::new(RefCell::new(Ghosting::DEFAULT));
+
+pub fn set_ghosting(ghosting: Ghosting) {
+    cortex_m::interrupt::free(|cs| *GHOSTING.borrow(cs).borrow_mut() = ghosting);
+}
+
+fn ghosting() -> Ghosting {
+    cortex_m::interrupt::free(|cs| *GHOSTING.borrow(cs).borrow())
+}
 
-#[derive(Debug, Eq, PartialEq, TryFromPrimitive, defmt::Format)]
+/// How often and how fast the key matrix is scanned
+#[derive(Debug, Clone, Copy, Eq, PartialEq, defmt::Format)]
+pub struct ScanConfig {
+    /// Scans per second, at least 1
+    pub rate_hz: u16,
+    /// See [`KeyMatrix::set_settle_us`]
+    pub settle_us: u8,
+}
+
+impl ScanConfig {
+    pub const DEFAULT: Self = Self {
+        rate_hz: 1000,
+        settle_us: keys::DEFAULT_SETTLE_US,
+    };
+
+    fn period(&self) -> Duration {
+        Duration::from_micros(1_000_000 / self.rate_hz.max(1) as u64)
+    }
+}
+
+static SCAN_CONFIG: Mutex<RefCell<ScanConfig>> = Mutex::new(RefCell::new(ScanConfig::DEFAULT));
+
+pub fn set_scan_config(config: ScanConfig) {
+    cortex_m::interrupt::free(|cs| *SCAN_CONFIG.borrow(cs).borrow_mut() = config);
+}
+
+fn scan_config() -> ScanConfig {
+    cortex_m::interrupt::free(|cs| *SCAN_CONFIG.borrow(cs).borrow())
+}
+
+/// The debounced state of every key, indexed by `Key as u8`. Shared with the command handler
+static KEY_STATES: Mutex<RefCell<BitArray<64>>> = Mutex::new(RefCell::new(BitArray::new()));
+
+/// Which keys are currently held, indexed by `Key as u8`
+pub fn key_states() -> BitArray<64> {
+    cortex_m::interrupt::free(|cs| KEY_STATES.borrow(cs).borrow().clone())
+}
+
+/// Debounce windows in ms, indexed by `Key as u8`
+struct DebounceWindows {
+    windows: [u16; NUM_KEYS],
+    /// Set until the input task has applied the windows to the matrix
+    changed: bool,
+}
+
+static DEBOUNCE_WINDOWS: Mutex<RefCell<DebounceWindows>> =
+    Mutex::new(RefCell::new(DebounceWindows {
+        windows: [debounce::DEFAULT_WINDOW_MS; NUM_KEYS],
+        changed: false,
+    }));
+
+/// Set the debounce window of a key, or of all keys for `Key::None`
+pub fn set_debounce(key: Key, window_ms: u16) {
+    cortex_m::interrupt::free(|cs| {
+        let mut d = DEBOUNCE_WINDOWS.borrow(cs).borrow_mut();
+        if key == Key::None {
+            d.windows = [window_ms; NUM_KEYS];
+        } else {
+            d.windows[key as usize] = window_ms;
+        }
+        d.changed = true;
+    })
+}
+
+/// The debounce windows, if they changed since the last call
+fn take_debounce_windows() -> Option<[u16; NUM_KEYS]> {
+    cortex_m::interrupt::free(|cs| {
+        let mut d = DEBOUNCE_WINDOWS.borrow(cs).borrow_mut();
+        if core::mem::take(&mut d.changed) {
+            Some(d.windows)
+        } else {
+            None
+        }
+    })
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive, defmt::Format)]
 #[repr(u8)]
 pub enum Key {
     None = 0,
@@ -156,26 +280,91 @@ pub fn make_key_table() -> [[Key; 8]; 8] {
 }
 
 #[embassy::task]
-pub async fn poll_input(mut matrix: KeyMatrix) {
+pub async fn poll_input(mut matrix: KeyMatrix, events: EventSender) {
     let table: [[Key; 8]; 8] = make_key_table();
+    for r in 0..8 {
+        for c in 0..8 {
+            if table[r][c] == Key::None {
+                matrix.mark_empty(KeyMatrix::idx_of(r, c));
+            }
+        }
+    }
+    let mut gestures = [Recognizer::default(); NUM_KEYS];
+    let mut next_scan = Instant::now();
     loop {
-        Timer::after(Duration::from_millis(10)).await;
+        let scan = scan_config();
+        // Don't try to catch up if a scan was late, just keep the rate from here
+        next_scan = (next_scan + scan.period()).max(Instant::now());
+        Timer::at(next_scan).await;
+        matrix.set_settle_us(scan.settle_us);
+        matrix.set_ghosting(ghosting());
+        if let Some(windows) = take_debounce_windows() {
+            for r in 0..8 {
+                for c in 0..8 {
+                    let key = table[r][c];
+                    if key != Key::None {
+                        matrix.set_debounce_ms(KeyMatrix::idx_of(r, c), windows[key as usize]);
+                    }
+                }
+            }
+        }
         let old_state = matrix.states.clone();
-        let changed = matrix.scan().await;
-        if !changed {
-            continue;
+        // Events are timestamped with the start of the scan that detected them
+        let now = Instant::now();
+        let changed = matrix.scan();
+        let now_ms = now.as_millis() as u32;
+        let time = cmd::timestamp(now);
+        if changed {
+            let mut keys = BitArray::<64>::new();
+            for r in 0..8 {
+                for c in 0..8 {
+                    if table[r][c] != Key::None {
+                        keys.set(
+                            table[r][c] as usize,
+                            matrix.states.get(KeyMatrix::idx_of(r, c)),
+                        );
+                    }
+                }
+            }
+            cortex_m::interrupt::free(|cs| *KEY_STATES.borrow(cs).borrow_mut() = keys);
         }
         for r in 0..8 {
             for c in 0..8 {
-                if table[r][c] == Key::None {
+                let key = table[r][c];
+                if key == Key::None {
                     continue;
                 }
                 let idx = KeyMatrix::idx_of(r, c);
+                let recognizer = &mut gestures[key as usize];
+                let config = gesture_config(key);
+                let mut gesture = None;
                 if old_state.get(idx) != matrix.states.get(idx) {
-                    if matrix.states.get(idx) {
-                        info!("Press {}", table[r][c])
+                    feedback::set_held(key, matrix.states.get(idx));
+                    let event = if matrix.states.get(idx) {
+                        info!("Press {}", key);
+                        gesture = recognizer.press(&config, now_ms);
+                        let uncertain = matrix.uncertain.get(idx);
+                        Message::KeyPress {
+                            key,
+                            time,
+                            uncertain,
+                        }
                     } else {
-                        info!("Release {}", table[r][c])
+                        info!("Release {}", key);
+                        recognizer.release(&config, now_ms);
+                        Message::KeyRelease { key, time }
+                    };
+                    if events.send(event).await.is_err() {
+                        error!("Event channel closed");
+                    }
+                } else {
+                    gesture = recognizer.poll(&config, now_ms);
+                }
+                if let Some(gesture) = gesture {
+                    info!("{} {}", gesture, key);
+                    let event = Message::Gesture { key, gesture, time };
+                    if events.send(event).await.is_err() {
+                        error!("Event channel closed");
                     }
                 }
             }
@@ -1,10 +1,11 @@
-use embassy::time::Duration;
-use embassy::time::Timer;
+use embassy::time::Instant;
 use embassy_stm32::gpio;
 use embassy_stm32::gpio::AnyPin;
 use embedded_hal::digital::v2::InputPin;
 use embedded_hal::digital::v2::OutputPin;
 
+use crate::debounce::{self, Debouncer};
+use crate::ghosting::{self, Ghosting};
 use crate::util::bitarray::BitArray;
 
 type AnyOutputPin = gpio::Output<'static, gpio::AnyPin>;
@@ -13,10 +14,27 @@ type AnyInputPin = gpio::Input<'static, gpio::AnyPin>;
 const ROWS: usize = 8;
 const COLS: usize = 8;
 
+const CYCLES_PER_US: u32 = crate::SYS_CK_MHZ;
+
+/// Default time the rows get to settle after driving a column
+pub const DEFAULT_SETTLE_US: u8 = 10;
+
 pub struct KeyMatrix {
     pub row_pins: [AnyInputPin; ROWS],
     pub col_pins: [AnyOutputPin; COLS],
+    /// Debounced key states
     pub states: BitArray<64>,
+    /// Keys that were ambiguous when their press was reported, see [`ghosting::resolve`]
+    pub uncertain: BitArray<64>,
+    /// Positions without a switch, which can only read as pressed because of a ghost
+    empty: BitArray<64>,
+    ghosting: Ghosting,
+    /// The ghosting mode changed, so keys held back by it have to be re-checked
+    ghosting_changed: bool,
+    settle_us: u8,
+    debouncer: Debouncer<64>,
+    /// Start of the last scan in ms since boot, as fed to the debouncer
+    last_scan_ms: u64,
 }
 
 impl KeyMatrix {
@@ -25,28 +43,104 @@ impl KeyMatrix {
             row_pins: row_pins.map(|x| gpio::Input::new(x, gpio::Pull::Down)),
             col_pins: col_pins.map(|x| gpio::Output::new(x, gpio::Level::Low, gpio::Speed::Low)),
             states: Default::default(),
+            uncertain: Default::default(),
+            empty: Default::default(),
+            ghosting: Ghosting::DEFAULT,
+            ghosting_changed: false,
+            settle_us: DEFAULT_SETTLE_US,
+            debouncer: Debouncer::new(debounce::DEFAULT_WINDOW_MS),
+            last_scan_ms: Instant::now().as_millis(),
         }
     }
 
+    /// Set the debounce window of the key at `idx`
+    pub fn set_debounce_ms(&mut self, idx: usize, window_ms: u16) {
+        self.debouncer.set_window(idx, window_ms);
+    }
+
+    /// Mark the position at `idx` as having no switch
+    pub fn mark_empty(&mut self, idx: usize) {
+        self.empty.set(idx, true);
+    }
+
+    pub fn set_ghosting(&mut self, ghosting: Ghosting) {
+        self.ghosting_changed |= self.ghosting != ghosting;
+        self.ghosting = ghosting;
+    }
+
+    /// Set how long to wait after driving a column before reading the rows
+    pub fn set_settle_us(&mut self, settle_us: u8) {
+        self.settle_us = settle_us;
+    }
+
     pub fn idx_of(r: usize, c: usize) -> usize {
         c * COLS + r
     }
 
-    /// Update the table; returns true if changed
-    pub async fn scan(&mut self) -> bool {
-        let mut has_changed = false;
+    /// Update the table; returns true if any reported state changed.
+    ///
+    /// Busy-waits for the settling delay after each column, so a scan blocks
+    /// the executor for `8 * settle_us` plus a few µs.
+    pub fn scan(&mut self) -> bool {
+        // Counted from boot rather than from the last scan, so the fraction of a ms left
+        // over carries into the next scan, and debounce windows keep their length
+        let now_ms = Instant::now().as_millis();
+        let elapsed_ms = now_ms - core::mem::replace(&mut self.last_scan_ms, now_ms);
+        let elapsed_ms = elapsed_ms.min(u16::MAX as u64) as u16;
+        let settle_cycles = self.settle_us as u32 * CYCLES_PER_US;
+        let mut debounced_changed = false;
         for c in 0..COLS {
             self.col_pins[c].set_high().unwrap();
-            Timer::after(Duration::from_millis(1)).await;
+            cortex_m::asm::delay(settle_cycles);
             for r in 0..ROWS {
-                let cur = self.row_pins[r].is_high().unwrap();
-                if self.states.get(Self::idx_of(r, c)) != cur {
-                    has_changed = true;
+                let idx = Self::idx_of(r, c);
+                let raw = self.row_pins[r].is_high().unwrap();
+                if self.debouncer.update(idx, raw, elapsed_ms) {
+                    debounced_changed = true;
                 }
-                self.states.set(Self::idx_of(r, c), cur);
             }
             self.col_pins[c].set_low().unwrap();
         }
+        let ghosting_changed = core::mem::take(&mut self.ghosting_changed);
+        if !debounced_changed && !ghosting_changed {
+            return false;
+        }
+        self.resolve_ghosts()
+    }
+
+    /// Copy the debounced states to [`Self::states`], handling ghosts as configured.
+    /// Returns true if any state changed
+    fn resolve_ghosts(&mut self) -> bool {
+        // With 8 rows, byte `c` of the state arrays holds the rows of column `c`
+        let mut debounced = [0u8; COLS];
+        for c in 0..COLS {
+            for r in 0..ROWS {
+                debounced[c] |= (self.debouncer.get(Self::idx_of(r, c)) as u8) << r;
+            }
+        }
+        let mut reported = [0u8; COLS];
+        reported.copy_from_slice(self.states.as_bytes());
+        let mut uncertain = [0u8; COLS];
+        uncertain.copy_from_slice(self.uncertain.as_bytes());
+        let mut populated = [0u8; COLS];
+        for (p, e) in populated.iter_mut().zip(self.empty.as_bytes()) {
+            *p = !e;
+        }
+
+        let has_changed = ghosting::resolve(
+            self.ghosting,
+            &populated,
+            &debounced,
+            &mut reported,
+            &mut uncertain,
+        );
+        for c in 0..COLS {
+            for r in 0..ROWS {
+                let idx = Self::idx_of(r, c);
+                self.states.set(idx, reported[c] & (1 << r) != 0);
+                self.uncertain.set(idx, uncertain[c] & (1 << r) != 0);
+            }
+        }
         has_changed
     }
 }
@@ -0,0 +1,101 @@
+use defmt::Format;
+use num_enum::TryFromPrimitive;
+
+use crate::encoder::EncoderId;
+use crate::input::Key;
+
+// Placeholder: the indices below follow the `Key` enum order, then the encoders, then the
+// indicators. Replace them with the strip order of the board once it has been traced
+
+/// LEDs that belong to neither a key nor an encoder
+#[derive(Debug, Clone, Copy, Eq, PartialEq, TryFromPrimitive, Format)]
+#[repr(u8)]
+pub enum Indicator {
+    A = 0,
+    B = 1,
+}
+
+/// The LED under a key, if it has one.
+/// The encoder click keys map to the LED of their encoder
+pub fn led_of_key(key: Key) -> Option<u8> {
+    let idx = match key {
+        Key::Channel0 => 0,
+        Key::Channel1 => 1,
+        Key::Channel2 => 2,
+        Key::Channel3 => 3,
+        Key::Channel4 => 4,
+        Key::Channel5 => 5,
+        Key::Channel6 => 6,
+        Key::Channel7 => 7,
+        Key::Channel8 => 8,
+        Key::Channel9 => 9,
+        Key::Seq0 => 10,
+        Key::Seq1 => 11,
+        Key::Seq2 => 12,
+        Key::Seq3 => 13,
+        Key::Seq4 => 14,
+        Key::Seq5 => 15,
+        Key::Seq6 => 16,
+        Key::Seq7 => 17,
+        Key::Seq8 => 18,
+        Key::Seq9 => 19,
+        Key::Seq10 => 20,
+        Key::Seq11 => 21,
+        Key::Seq12 => 22,
+        Key::Seq13 => 23,
+        Key::Seq14 => 24,
+        Key::Seq15 => 25,
+        Key::Shift => 26,
+        Key::Sends => 27,
+        Key::Plus => 28,
+        Key::Mixer => 29,
+        Key::Minus => 30,
+        Key::Fx1 => 31,
+        Key::Fx2 => 32,
+        Key::Master => 33,
+        Key::Play => 34,
+        Key::Record => 35,
+        Key::Arp => 36,
+        Key::Slots => 37,
+        Key::Twist1 => 38,
+        Key::Twist2 => 39,
+        Key::Looper => 40,
+        Key::External => 41,
+        Key::Sampler => 42,
+        Key::Envelope => 43,
+        Key::Voices => 44,
+        Key::Settings => 45,
+        Key::Sequencer => 46,
+        Key::Synth => 47,
+        Key::BlueEncClick => led_of_encoder(EncoderId::Blue),
+        Key::GreenEncClick => led_of_encoder(EncoderId::Green),
+        Key::YellowEncClick => led_of_encoder(EncoderId::Yellow),
+        Key::RedEncClick => led_of_encoder(EncoderId::Red),
+        Key::None
+        | Key::UnassignedA
+        | Key::UnassignedB
+        | Key::UnassignedC
+        | Key::UnassignedD
+        | Key::UnassignedE
+        | Key::UnassignedF => return None,
+    };
+    Some(idx)
+}
+
+/// The LED next to an encoder
+pub fn led_of_encoder(encoder: EncoderId) -> u8 {
+    match encoder {
+        EncoderId::Blue => 48,
+        EncoderId::Green => 49,
+        EncoderId::Yellow => 50,
+        EncoderId::Red => 51,
+    }
+}
+
+/// The LED of an indicator
+pub fn led_of_indicator(indicator: Indicator) -> u8 {
+    match indicator {
+        Indicator::A => 52,
+        Indicator::B => 53,
+    }
+}
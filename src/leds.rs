@@ -1,71 +1,117 @@
-use embassy_stm32::spi::{Mode, Phase, Polarity};
+use embassy_stm32::dma::NoDma;
+use embassy_stm32::peripherals;
+use embassy_stm32::spi::{Mode, Phase, Polarity, Spi};
 use rgb::RGB8;
 
+/// Number of LEDs in the strip
+pub const NUM_LEDS: usize = 54;
+
 pub const MODE: Mode = Mode {
     polarity: Polarity::IdleLow,
     phase: Phase::CaptureOnFirstTransition,
 };
 
+/// SPI bytes needed for one color byte, two bits per SPI byte
+const SPI_BYTES_PER_BYTE: usize = 4;
+
+/// Low bytes sent after the data to latch it. Needs to be at least 50us
+const RESET_BYTES: usize = 20;
+
+/// Size of the SPI buffer for a whole strip, including the reset time
+pub const BUFFER_SIZE: usize = NUM_LEDS * 3 * SPI_BYTES_PER_BYTE + RESET_BYTES;
+
+pub type Leds = Ws2812<Spi<'static, peripherals::SPI1, peripherals::DMA1_CH3, NoDma>>;
+
+/// Expand a single color byte into its SPI bit pattern
+fn encode_byte(mut data: u8, out: &mut [u8]) {
+    // Send two bits in one spi byte. High time first, then the low time
+    // The maximum for T0H is 500ns, the minimum for one bit 1063 ns.
+    // These result in the upper and lower spi frequency limits
+    let patterns = [0b0100_0100, 0b0100_0111, 0b0111_0100, 0b0111_0111];
+    for byte in out.iter_mut().take(SPI_BYTES_PER_BYTE) {
+        let bits = (data & 0b1100_0000) >> 6;
+        *byte = patterns[bits as usize];
+        data <<= 2;
+    }
+}
+
+/// Encode colors into the SPI bit stream for ws2812 devices, followed by the reset time.
+/// Colors that do not fit in `buffer` are ignored.
+///
+/// Returns the number of bytes to send
+pub fn encode(colors: impl Iterator<Item = RGB8>, buffer: &mut [u8]) -> usize {
+    let mut len = 0;
+    let pixel_len = 3 * SPI_BYTES_PER_BYTE;
+    for color in colors {
+        if len + pixel_len + RESET_BYTES > buffer.len() {
+            break;
+        }
+        // ws2812 expects GRB order
+        for (i, byte) in [color.g, color.r, color.b].into_iter().enumerate() {
+            encode_byte(byte, &mut buffer[len + i * SPI_BYTES_PER_BYTE..]);
+        }
+        len += pixel_len;
+    }
+    let end = (len + RESET_BYTES).min(buffer.len());
+    buffer[len..end].fill(0);
+    end
+}
+
 pub struct Ws2812<SPI> {
     spi: SPI,
+    buffer: [u8; BUFFER_SIZE],
 }
 
-//
-
 impl<SPI> Ws2812<SPI>
 where
-    SPI: embedded_hal::blocking::spi::Write<u8>,
+    SPI: embassy_traits::spi::Write<u8>,
 {
     /// Use ws2812 devices via spi
     ///
     /// The SPI bus should run within 2 MHz to 3.8 MHz
     pub fn new(spi: SPI) -> Self {
-        Self { spi }
-    }
-
-    /// Write a single byte for ws2812 devices
-    async fn write_byte(&mut self, mut data: u8) -> Result<(), SPI::Error> {
-        // Send two bits in one spi byte. High time first, then the low time
-        // The maximum for T0H is 500ns, the minimum for one bit 1063 ns.
-        // These result in the upper and lower spi frequency limits
-        let patterns = [0b0100_0100, 0b0100_0111, 0b0111_0100, 0b0111_0111];
-        for _ in 0..4 {
-            let bits = (data & 0b1100_0000) >> 6;
-            self.spi.write(&[patterns[bits as usize]])?;
-            data <<= 2;
+        Self {
+            spi,
+            buffer: [0; BUFFER_SIZE],
         }
-        Ok(())
     }
 
-    async fn flush(&mut self) -> Result<(), SPI::Error> {
-        for _ in 0..20 {
-            self.spi.write(&[0])?;
-        }
-        Ok(())
-    }
-
-    /// Write all the items of an iterator to a ws2812 strip
+    /// Write all the items of an iterator to a ws2812 strip, in a single SPI transfer
     pub async fn write<T, I>(&mut self, iterator: T) -> Result<(), SPI::Error>
     where
         T: Iterator<Item = I>,
         I: Into<RGB8>,
     {
-        // We introduce an offset in the fifo here, so there's always one byte in transit
-        // Some MCUs (like the stm32f1) only a one byte fifo, which would result
-        // in overrun error if two bytes need to be stored
-        // self.spi.write(0);
-        if cfg!(feature = "mosi_idle_high") {
-            self.flush().await?;
-        }
+        let len = encode(iterator.map(Into::into), &mut self.buffer);
+        self.spi.write(&self.buffer[..len]).await
+    }
+}
 
-        for item in iterator {
-            let item: RGB8 = item.into();
-            self.write_byte(item.g).await?;
-            self.write_byte(item.r).await?;
-            self.write_byte(item.b).await?;
-        }
-        self.flush().await?;
-        // Now, resolve the offset we introduced at the beginning
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_grb_msb_first() {
+        let mut buf = [0xAA; BUFFER_SIZE];
+        let len = encode([RGB8::new(0xFF, 0x00, 0b1001_0110)].into_iter(), &mut buf);
+        assert_eq!(len, 12 + RESET_BYTES);
+        assert_eq!(buf[0..4], [0b0100_0100; 4]);
+        assert_eq!(buf[4..8], [0b0111_0111; 4]);
+        assert_eq!(
+            buf[8..12],
+            [0b0111_0100, 0b0100_0111, 0b0100_0111, 0b0111_0100]
+        );
+        assert!(buf[12..len].iter().all(|&b| b == 0));
+        assert_eq!(buf[len], 0xAA);
+    }
+
+    #[test]
+    fn full_strip_fits_buffer() {
+        let mut buf = [0; BUFFER_SIZE];
+        let colors = [RGB8::new(1, 2, 1); NUM_LEDS + 1];
+        let len = encode(colors.into_iter(), &mut buf);
+        assert_eq!(len, BUFFER_SIZE);
+        assert_eq!(buf[BUFFER_SIZE - RESET_BYTES - 1], 0b0100_0111);
     }
 }
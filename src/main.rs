@@ -6,44 +6,74 @@
 #![feature(generators, generator_trait)]
 
 mod cmd;
+mod color;
+mod crc;
+mod debounce;
+mod effects;
+mod encoder;
+mod events;
+mod feedback;
+mod framebuffer;
+mod gesture;
+mod ghosting;
 mod i2c;
 mod input;
 mod keys;
+mod ledmap;
 mod leds;
+mod power;
+mod registers;
 mod util;
 
-use defmt::{info, unwrap};
+use cmd::{Command, ErrorCode, Message};
+use cortex_m::peripheral::SCB;
+use defmt::{trace, unwrap, warn};
 use defmt_rtt as _;
+use embassy::channel::mpsc::{self, Channel, WithNoThreads};
 use embassy::executor::Spawner;
-use embassy::time::{Duration, Timer};
+use embassy::time::{Instant, Timer};
+use embassy::util::Forever;
 use embassy_stm32::dma::NoDma;
-use embassy_stm32::gpio::{Level, Output, Pin, Speed};
+use embassy_stm32::exti::{AnyChannel, Channel as _, ExtiInput};
+use embassy_stm32::gpio::{AnyPin, Input, Level, Output, Pin, Pull, Speed};
 use embassy_stm32::pac::AFIO;
 use embassy_stm32::time::U32Ext;
-use embassy_stm32::{interrupt, peripherals, spi, Config, Peripherals};
+use embassy_stm32::{interrupt, spi, Config, Peripherals};
 use embedded_hal::digital::v2::OutputPin;
+use encoder::{Encoder, EncoderId};
+use framebuffer::{LedCommand, LEDS_PER_COMMAND};
+use futures::future::{select, Either};
 use futures::pin_mut;
+use i2c::FrameError;
 use keys::KeyMatrix;
 // global logger
 use panic_probe as _;
 use rgb::RGB8;
 
-use core::sync::atomic::{AtomicUsize, Ordering};
-
 defmt::timestamp! {
-    "{=u64}", {
-        static COUNT: AtomicUsize = AtomicUsize::new(0);
-        // NOTE(no-CAS) `timestamps` runs with interrupts disabled
-        let n = COUNT.load(Ordering::Relaxed);
-        COUNT.store(n + 1, Ordering::Relaxed);
-        n as u64
+    "{=u64:µs}", {
+        // The TIM2 time driver reads its overflow count and counter without locking,
+        // so this is fine to call from interrupt handlers.
+        Instant::now().as_micros()
     }
 }
 
+/// Input events and LED task errors on their way to the I2C TX queue
+static EVENTS: Forever<Channel<WithNoThreads, Message, { input::EVENT_CHANNEL_SIZE }>> =
+    Forever::new();
+
+/// Framebuffer updates on their way from the host to the LED task
+static LED_COMMANDS: Forever<
+    Channel<WithNoThreads, (u8, LedCommand), { framebuffer::LED_CHANNEL_SIZE }>,
+> = Forever::new();
+
+/// `sys_ck`, also used to busy-wait for a number of µs
+const SYS_CK_MHZ: u32 = 48;
+
 fn config() -> Config {
     let mut config = Config::default();
     config.rcc.hse = Some(16.mhz().into());
-    config.rcc.sys_ck = Some(48.mhz().into());
+    config.rcc.sys_ck = Some(SYS_CK_MHZ.mhz().into());
     config.rcc.hclk = Some(48.mhz().into());
     config.rcc.pclk1 = Some(24.mhz().into());
     config.rcc.pclk2 = Some(48.mhz().into());
@@ -51,22 +81,10 @@ fn config() -> Config {
     config
 }
 
-type Leds = leds::Ws2812<spi::Spi<'static, peripherals::SPI1, NoDma, NoDma>>;
-
-#[embassy::task]
-async fn test_leds(mut leds: Leds) {
-    let mut colors = [RGB8::default(); 54];
-    loop {
-        for i in 0..colors.len() {
-            colors[i] = RGB8::new(0xFF, 00, 0x20);
-            leds.write(colors.clone().into_iter()).await.unwrap();
-            Timer::after(Duration::from_millis(300)).await;
-        }
-        for i in 0..colors.len() {
-            colors[i] = RGB8::new(0, 0, 0);
-            leds.write(colors.clone().into_iter()).await.unwrap();
-            Timer::after(Duration::from_millis(300)).await;
-        }
+/// Queue a reply for the host
+fn reply<T: i2c::InstanceExt>(i2c: &mut i2c::I2cSlave<'_, T>, msg: Message) {
+    if i2c.enqueue(msg.encode()).is_err() {
+        warn!("TX queue full, dropping reply");
     }
 }
 
@@ -96,6 +114,32 @@ async fn main(spawner: Spawner, p: Peripherals) {
         ],
     );
 
+    // Placeholder pins, not checked against the front panel schematic. Confirm them
+    // before relying on the encoders
+    let exti = |pin: AnyPin, ch: AnyChannel| ExtiInput::new(Input::new(pin, Pull::Up), ch);
+    let encoders = [
+        Encoder::new(
+            EncoderId::Blue,
+            exti(p.PA0.degrade(), p.EXTI0.degrade()),
+            exti(p.PA1.degrade(), p.EXTI1.degrade()),
+        ),
+        Encoder::new(
+            EncoderId::Green,
+            exti(p.PA2.degrade(), p.EXTI2.degrade()),
+            exti(p.PA3.degrade(), p.EXTI3.degrade()),
+        ),
+        Encoder::new(
+            EncoderId::Yellow,
+            exti(p.PC10.degrade(), p.EXTI10.degrade()),
+            exti(p.PC11.degrade(), p.EXTI11.degrade()),
+        ),
+        Encoder::new(
+            EncoderId::Red,
+            exti(p.PC12.degrade(), p.EXTI12.degrade()),
+            exti(p.PA15.degrade(), p.EXTI15.degrade()),
+        ),
+    ];
+
     let mut spi_config = spi::Config::default();
     spi_config.mode = leds::MODE;
     let spi = spi::Spi::new(
@@ -103,7 +147,7 @@ async fn main(spawner: Spawner, p: Peripherals) {
         p.PA5,
         p.PA7,
         p.PA6,
-        NoDma,
+        p.DMA1_CH3,
         NoDma,
         3.mhz(),
         spi_config,
@@ -112,15 +156,17 @@ async fn main(spawner: Spawner, p: Peripherals) {
 
     let mut i2cstate = i2c::State::new();
     let mut i2c = unsafe {
-        // Safety: 
+        // Safety:
         i2c::I2cSlave::new_unchecked(
             &mut i2cstate,
             p.I2C1,
             p.PB6,
             p.PB7,
+            p.PA8, // Data ready, active low
             interrupt::take!(I2C1_EV),
             interrupt::take!(I2C1_ER),
             0x77,
+            0x76, // Register mode
         )
     };
 
@@ -129,12 +175,174 @@ async fn main(spawner: Spawner, p: Peripherals) {
         AFIO.mapr().modify(|m| m.set_swj_cfg(010u8));
     }
 
-    unwrap!(spawner.spawn(input::poll_input(km)));
-    // unwrap!(spawner.spawn(test_leds(leds)));
+    let (event_tx, mut event_rx) = mpsc::split(EVENTS.put(Channel::new()));
+
+    for encoder in encoders {
+        unwrap!(spawner.spawn(encoder::poll_encoder(encoder, event_tx.clone())));
+    }
+    unwrap!(spawner.spawn(input::poll_input(km, event_tx.clone())));
+    let (led_tx, led_rx) = mpsc::split(LED_COMMANDS.put(Channel::new()));
+    unwrap!(spawner.spawn(framebuffer::refresh_leds(leds, led_rx, event_tx)));
     let mut led = Output::new(p.PC6, Level::High, Speed::Low);
 
+    let mut next_check = Instant::now() + i2c::STUCK_TIMEOUT;
     loop {
-        let packet = i2c.receive_message().await;
-        info!("Got packet: {}", packet);
+        // Every key and encoder change comes with an event, which wakes this loop
+        i2c.update_registers();
+        let received = {
+            let frame = i2c.receive_message();
+            let event = event_rx.recv();
+            // Deadline kept across iterations, so a steady stream of events can't starve it
+            let check = Timer::at(next_check);
+            pin_mut!(frame, event, check);
+            match select(frame, select(event, check)).await {
+                Either::Left((received, _)) => received,
+                Either::Right((Either::Left((event, _)), _)) => {
+                    if let Some(event) = event {
+                        if i2c.enqueue(event.encode()).is_err() {
+                            warn!("TX queue full, dropping event");
+                        }
+                    }
+                    continue;
+                }
+                Either::Right((Either::Right(_), _)) => {
+                    next_check = Instant::now() + i2c::STUCK_TIMEOUT;
+                    if i2c.recover_if_stuck() {
+                        let count = i2c.diagnostics().resets;
+                        warn!("I2C peripheral was stuck, reset it ({} so far)", count);
+                    }
+                    continue;
+                }
+            }
+        };
+        let frame = match received {
+            Ok(frame) => frame,
+            Err(e) => {
+                let (command, code) = match e {
+                    FrameError::TooLong(command) => {
+                        warn!("Frame for command {} too long", command);
+                        (command, ErrorCode::FrameTooLong)
+                    }
+                    FrameError::BadPec(command) => {
+                        let count = i2c.diagnostics().bad_pecs;
+                        warn!("Bad PEC on frame for command {}, {} so far", command, count);
+                        (command, ErrorCode::BadPec)
+                    }
+                };
+                reply(&mut i2c, Message::Error { command, code });
+                continue;
+            }
+        };
+        let command = frame.first().copied().unwrap_or(0);
+        let cmd = match Command::decode(&frame) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                warn!("Invalid frame {}: {}", &frame[..], e);
+                let code = e.code();
+                reply(&mut i2c, Message::Error { command, code });
+                continue;
+            }
+        };
+        trace!("Got frame: {}", &frame[..]);
+        match cmd {
+            Command::SetLed { idx, color } => {
+                let cmd = LedCommand::Set { idx, color };
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetLedRange { start, end, color } => {
+                let cmd = LedCommand::SetRange { start, end, color };
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetAllLeds { color } => {
+                led_tx.send((command, LedCommand::SetAll(color))).await.ok();
+            }
+            Command::SetLeds { start, len, colors } => {
+                let chunks = colors[..len as usize].chunks(LEDS_PER_COMMAND);
+                for (i, chunk) in chunks.enumerate() {
+                    let mut colors = [RGB8::default(); LEDS_PER_COMMAND];
+                    colors[..chunk.len()].copy_from_slice(chunk);
+                    let cmd = LedCommand::SetLeds {
+                        start: start + (i * LEDS_PER_COMMAND) as u8,
+                        len: chunk.len() as u8,
+                        colors,
+                    };
+                    led_tx.send((command, cmd)).await.ok();
+                }
+            }
+            Command::StartEffect { start, end, effect } => {
+                let cmd = LedCommand::StartEffect { start, end, effect };
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetFeedback { enabled, color } => feedback::set_enabled(enabled, color),
+            Command::SetKeyFeedback { key, enabled } => feedback::set_key_enabled(key, enabled),
+            Command::SetBrightness { brightness } => {
+                let cmd = LedCommand::SetBrightness(brightness);
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetGamma { gamma } => {
+                let cmd = LedCommand::SetGamma(gamma);
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetGammaTable { offset, data } => {
+                let cmd = LedCommand::SetGammaTable { offset, data };
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetPowerLimit { limit_ma } => {
+                let cmd = LedCommand::SetPowerLimit(limit_ma);
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetKeyLed { key, color } => {
+                if let Some(idx) = ledmap::led_of_key(key) {
+                    let cmd = LedCommand::Set { idx, color };
+                    led_tx.send((command, cmd)).await.ok();
+                } else {
+                    let code = ErrorCode::NoLed;
+                    reply(&mut i2c, Message::Error { command, code });
+                }
+            }
+            Command::SetEncoderLed { encoder, color } => {
+                let idx = ledmap::led_of_encoder(encoder);
+                let cmd = LedCommand::Set { idx, color };
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetIndicatorLed { indicator, color } => {
+                let idx = ledmap::led_of_indicator(indicator);
+                let cmd = LedCommand::Set { idx, color };
+                led_tx.send((command, cmd)).await.ok();
+            }
+            Command::SetStatusLed { on } => {
+                if on {
+                    led.set_high().unwrap();
+                } else {
+                    led.set_low().unwrap();
+                }
+            }
+            Command::SetDebounce { key, window_ms } => input::set_debounce(key, window_ms),
+            Command::SetEncoderAcceleration { encoder, curve } => {
+                encoder::set_acceleration(encoder, curve);
+            }
+            Command::SetGestureConfig { key, config } => input::set_gesture_config(key, config),
+            Command::SetGhosting { ghosting } => input::set_ghosting(ghosting),
+            Command::SetScanConfig(config) => input::set_scan_config(config),
+            Command::RequestKeyState => {
+                let keys = input::key_states();
+                let encoders = encoder::positions().map(|p| p as i16);
+                reply(&mut i2c, Message::KeyState { keys, encoders });
+            }
+            Command::RequestVersion => reply(&mut i2c, Message::Version),
+            Command::RequestCurrent => {
+                reply(&mut i2c, Message::Current(power::current_estimate()));
+            }
+            Command::RequestTick => {
+                let ticks = Instant::now().as_ticks();
+                reply(&mut i2c, Message::Tick { ticks });
+            }
+            Command::RequestDiagnostics => {
+                let diagnostics = i2c.diagnostics();
+                reply(&mut i2c, Message::Diagnostics(diagnostics));
+            }
+            Command::ClearDiagnostics => i2c.clear_diagnostics(),
+            Command::Reset => SCB::sys_reset(),
+        }
     }
 }
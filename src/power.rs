@@ -0,0 +1,112 @@
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use rgb::RGB8;
+
+/// Current drawn by one fully lit color channel of a ws2812, in µA
+pub const CHANNEL_UA: [u32; 3] = [16_000, 16_000, 16_000];
+/// Current drawn by a ws2812 with all channels off, in µA
+pub const IDLE_UA: u32 = 600;
+/// Default limit for the whole strip, in mA
+pub const DEFAULT_LIMIT_MA: u16 = 1000;
+
+/// Current needed by the last frame before limiting, in mA
+static REQUESTED_MA: AtomicU16 = AtomicU16::new(0);
+/// Current drawn by the last frame after limiting, in mA
+static OUTPUT_MA: AtomicU16 = AtomicU16::new(0);
+/// The limit used for the last frame, in mA
+static LIMIT_MA: AtomicU16 = AtomicU16::new(DEFAULT_LIMIT_MA);
+
+/// Estimated current of the last frame sent to the strip
+#[derive(Debug, Clone, Copy, Eq, PartialEq, defmt::Format)]
+pub struct CurrentEstimate {
+    pub requested_ma: u16,
+    pub output_ma: u16,
+    pub limit_ma: u16,
+}
+
+pub fn current_estimate() -> CurrentEstimate {
+    CurrentEstimate {
+        requested_ma: REQUESTED_MA.load(Ordering::Relaxed),
+        output_ma: OUTPUT_MA.load(Ordering::Relaxed),
+        limit_ma: LIMIT_MA.load(Ordering::Relaxed),
+    }
+}
+
+/// Current drawn by the color channels alone, in µA
+fn channels_ua(colors: &[RGB8]) -> u32 {
+    colors
+        .iter()
+        .map(|c| {
+            (c.r as u32 * CHANNEL_UA[0] + c.g as u32 * CHANNEL_UA[1] + c.b as u32 * CHANNEL_UA[2])
+                / 255
+        })
+        .sum()
+}
+
+fn to_ma(ua: u32) -> u16 {
+    (ua / 1000).min(u16::MAX as u32) as u16
+}
+
+/// Scale down the whole frame so the estimated current stays below `limit_ma`.
+/// All channels are scaled by the same factor, which preserves hue. A limit of 0
+/// disables limiting.
+///
+/// The estimate is published for [`current_estimate`].
+pub fn limit(colors: &mut [RGB8], limit_ma: u16) {
+    let idle = IDLE_UA * colors.len() as u32;
+    let requested = channels_ua(colors);
+    let budget = (limit_ma as u32 * 1000).saturating_sub(idle);
+    if limit_ma != 0 && requested > budget {
+        // budget < requested, which is a few A at most, so this can't overflow
+        for c in colors.iter_mut() {
+            *c = RGB8::new(
+                (c.r as u32 * budget / requested) as u8,
+                (c.g as u32 * budget / requested) as u8,
+                (c.b as u32 * budget / requested) as u8,
+            );
+        }
+    }
+    REQUESTED_MA.store(to_ma(idle + requested), Ordering::Relaxed);
+    OUTPUT_MA.store(to_ma(idle + channels_ua(colors)), Ordering::Relaxed);
+    LIMIT_MA.store(limit_ma, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_adds_channels() {
+        assert_eq!(channels_ua(&[RGB8::new(255, 0, 0)]), CHANNEL_UA[0]);
+        assert_eq!(
+            channels_ua(&[RGB8::new(255, 255, 0), RGB8::new(0, 0, 255)]),
+            CHANNEL_UA.iter().sum()
+        );
+        assert_eq!(channels_ua(&[RGB8::default(); 4]), 0);
+    }
+
+    #[test]
+    fn under_limit_is_unchanged() {
+        let mut colors = [RGB8::new(255, 255, 255); 10];
+        limit(&mut colors, 1000);
+        assert_eq!(colors, [RGB8::new(255, 255, 255); 10]);
+    }
+
+    #[test]
+    fn scaling_keeps_ratios_and_meets_limit() {
+        let mut colors = [RGB8::new(200, 100, 50); 54];
+        limit(&mut colors, 500);
+        let c = colors[0];
+        assert!(c.r < 200);
+        assert!((c.r as i16 - 2 * c.g as i16).abs() <= 1);
+        assert!((c.g as i16 - 2 * c.b as i16).abs() <= 1);
+        assert!(to_ma(IDLE_UA * 54 + channels_ua(&colors)) <= 500);
+    }
+
+    #[test]
+    fn zero_disables_limit() {
+        let mut colors = [RGB8::new(255, 255, 255); 54];
+        limit(&mut colors, 0);
+        assert_eq!(colors, [RGB8::new(255, 255, 255); 54]);
+    }
+}
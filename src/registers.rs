@@ -0,0 +1,116 @@
+use crate::cmd::VERSION;
+use crate::encoder::{self, NUM_ENCODERS};
+use crate::i2c::Diagnostics;
+use crate::input;
+use crate::util::bitarray::BitArray;
+
+/// `[major, minor, patch]`
+pub const VERSION_REG: u8 = 0x00;
+/// Bit 0: events are waiting in [`EVENT_REG`]
+pub const STATUS_REG: u8 = 0x04;
+/// One bit per [`input::Key`], LSB first, like [`crate::cmd::Message::KeyState`]
+pub const KEYS_REG: u8 = 0x10;
+/// Encoder positions as wrapping i16, in [`encoder::EncoderId`] order, like
+/// [`crate::cmd::Message::KeyState`]
+pub const ENCODERS_REG: u8 = 0x18;
+/// Reading from here pops the oldest event and returns its frame, `[type, len, payload...]`.
+/// The event is popped by the read, even if it stops before the end of the frame
+pub const EVENT_REG: u8 = 0x20;
+/// [`Diagnostics`] counters, laid out like [`Diagnostics::to_bytes`]
+pub const DIAGNOSTICS_REG: u8 = 0x40;
+
+const MAP_SIZE: usize = DIAGNOSTICS_REG as usize + Diagnostics::SIZE;
+
+/// The register map, as bytes
+pub type Map = [u8; MAP_SIZE];
+
+/// Build the version, key and encoder registers. Takes critical sections, so this is
+/// called from thread mode, and the I2C interrupt only copies the result in [`read`]
+pub fn snapshot() -> Map {
+    build(&input::key_states(), &encoder::positions())
+}
+
+fn build(keys: &BitArray<64>, positions: &[i32; NUM_ENCODERS]) -> Map {
+    let mut map = [0; MAP_SIZE];
+    let at = |reg: u8| reg as usize;
+
+    map[at(VERSION_REG)..][..3].copy_from_slice(&VERSION);
+    map[at(KEYS_REG)..][..8].copy_from_slice(keys.as_bytes());
+    for (i, pos) in positions.iter().enumerate() {
+        map[at(ENCODERS_REG) + 2 * i..][..2].copy_from_slice(&(*pos as i16).to_le_bytes());
+    }
+    map
+}
+
+/// Fill in the status and diagnostics registers of a [`snapshot`], and return the bytes
+/// from `start` on, for the I2C register mode.
+///
+/// The host writes a register address, then reads from it with a repeated start, like
+/// with `i2cget`/`i2cdump`. Multi-byte values are little endian, and reads continue into
+/// the following registers. Registers are read-only, there is no PEC in this mode.
+/// Unused registers, and anything past the map, read as 0. [`EVENT_REG`] is handled by
+/// the I2C driver, as reading it has side effects
+pub fn read<'a>(
+    map: &'a mut Map,
+    start: u8,
+    events_pending: bool,
+    diagnostics: &Diagnostics,
+) -> &'a [u8] {
+    map[STATUS_REG as usize] = events_pending as u8;
+    map[DIAGNOSTICS_REG as usize..].copy_from_slice(&diagnostics.to_bytes());
+    map.get(start as usize..).unwrap_or(&[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map() -> Map {
+        let mut keys = BitArray::new();
+        keys.set(0, true);
+        keys.set(63, true);
+        build(&keys, &[1, -1, 0x1_2345, i32::MIN])
+    }
+
+    #[test]
+    fn snapshot_layout() {
+        let map = map();
+        assert_eq!(map[..3], VERSION);
+        assert_eq!(map[KEYS_REG as usize..][..8], [1, 0, 0, 0, 0, 0, 0, 0x80]);
+        assert_eq!(
+            map[ENCODERS_REG as usize..][..8],
+            [0x01, 0x00, 0xFF, 0xFF, 0x45, 0x23, 0x00, 0x00]
+        );
+        // Unused registers in between
+        assert_eq!(map[3..KEYS_REG as usize], [0; 13]);
+        assert_eq!(map[EVENT_REG as usize..DIAGNOSTICS_REG as usize], [0; 32]);
+    }
+
+    #[test]
+    fn read_fills_in_status_and_diagnostics() {
+        let mut map = map();
+        let diagnostics = Diagnostics {
+            frames_received: 0x0403_0201,
+            resets: 7,
+            ..Default::default()
+        };
+        let bytes = read(&mut map, STATUS_REG, true, &diagnostics);
+        assert_eq!(bytes[0], 1);
+        assert_eq!(bytes.len(), MAP_SIZE - STATUS_REG as usize);
+
+        let bytes = read(&mut map, DIAGNOSTICS_REG, false, &diagnostics);
+        assert_eq!(bytes, diagnostics.to_bytes());
+        assert_eq!(bytes[..4], [1, 2, 3, 4]);
+        assert_eq!(map[STATUS_REG as usize], 0);
+    }
+
+    #[test]
+    fn reads_past_the_end_are_empty() {
+        let mut map = map();
+        let diagnostics = Diagnostics::default();
+        let last = (MAP_SIZE - 1) as u8;
+        assert_eq!(read(&mut map, last, false, &diagnostics).len(), 1);
+        assert!(read(&mut map, last + 1, false, &diagnostics).is_empty());
+        assert!(read(&mut map, 0xFF, false, &diagnostics).is_empty());
+    }
+}
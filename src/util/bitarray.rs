@@ -14,6 +14,16 @@ where
     [u8; (BITS + 7) / 8]: Default,
     [u8; (BITS + 7) / 8]: Format,
 {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; (BITS + 7) / 8],
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
     pub fn get(&self, idx: usize) -> bool {
         self.bytes[idx / 8] & (1 << (idx % 8)) != 0
     }